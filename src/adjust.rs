@@ -0,0 +1,165 @@
+use bitcode::{Encode, Decode};
+use image::RgbImage;
+
+/// Exposure and colour corrections, applied to the crops when they are written.
+///
+/// The default changes nothing.
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Debug)]
+pub struct Adjustments {
+    /// Added to every channel, as a fraction of full scale
+    pub brightness: f32,
+    /// Stretches the channels away from mid-grey
+    pub contrast: f32,
+    /// Greater than 1 lightens the midtones
+    pub gamma: f32,
+    /// White balance: positive is warmer (more red, less blue)
+    pub temperature: f32,
+}
+
+impl Default for Adjustments {
+    fn default() -> Self { Self { brightness: 0.0, contrast: 1.0, gamma: 1.0, temperature: 0.0 } }
+}
+
+/// How far the red and blue channels are scaled at the extremes of `temperature`
+const TEMPERATURE_RANGE: f32 = 0.3;
+
+/// A setting which can be changed from the cropper
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Setting { Brightness, Contrast, Gamma, Temperature }
+
+impl Adjustments {
+    pub fn is_neutral(&self) -> bool { *self == Self::default() }
+
+    /// Change `setting` by `steps` increments, staying within sensible limits.
+    pub fn nudge(&mut self, setting: Setting, steps: f32) {
+        let (value, step, min, max) = match setting {
+            Setting::Brightness  => (&mut self.brightness , 0.02, -0.5, 0.5),
+            Setting::Contrast    => (&mut self.contrast   , 0.05,  0.5, 2.0),
+            Setting::Gamma       => (&mut self.gamma      , 0.05,  0.5, 2.5),
+            Setting::Temperature => (&mut self.temperature, 0.05, -1.0, 1.0),
+        };
+        // Rounded, so that going up and back down returns to exactly where we started
+        *value = ((*value + step * steps) / step).round() * step;
+        *value = value.clamp(min, max);
+    }
+
+    /// Correct `image` in place.
+    pub fn apply(&self, image: &mut RgbImage) {
+        if self.is_neutral() { return }
+        let warmth = self.temperature * TEMPERATURE_RANGE;
+        let [r, g, b] = [1.0 + warmth, 1.0, 1.0 - warmth].map(|balance| self.table(balance));
+        for pixel in image.pixels_mut() {
+            let [pr, pg, pb] = pixel.0;
+            pixel.0 = [r[pr as usize], g[pg as usize], b[pb as usize]];
+        }
+    }
+
+    /// Lookup table for a channel which is scaled by `balance`
+    fn table(&self, balance: f32) -> [u8; 256] {
+        std::array::from_fn(|c| {
+            let v = c as f32 / 255.0 * balance + self.brightness;
+            let v = (v - 0.5) * self.contrast + 0.5;
+            let v = v.clamp(0.0, 1.0).powf(1.0 / self.gamma);
+            (v * 255.0).round() as u8
+        })
+    }
+
+    /// Corrections which bring a face of average `colour` (see `face_colour`)
+    /// to the `target` colour: the brightness through the gamma, so that
+    /// neither black nor white move, and the skin tone through the white balance.
+    fn matching(colour: [f32; 3], target: [f32; 3]) -> Self {
+        let [r, g, b] = colour;
+        let [target_r, _, target_b] = target;
+        // Solve (1 + w) / (1 - w) = k for the warmth w which brings the
+        // red/blue ratio to that of the target
+        let k = (target_r / target_b.max(0.01)) / (r / b.max(0.01));
+        let temperature = ((k - 1.0) / (k + 1.0) / TEMPERATURE_RANGE).clamp(-1.0, 1.0);
+        // The white balance is applied before the gamma
+        let warmth = temperature * TEMPERATURE_RANGE;
+        let balanced = [r * (1.0 + warmth), g, b * (1.0 - warmth)].map(|c| c.min(1.0));
+        let lightness = luminance(balanced).clamp(0.01, 0.99);
+        let target_lightness = luminance(target).clamp(0.01, 0.99);
+        let gamma = lightness.ln() / target_lightness.ln();
+        Self { gamma: gamma.clamp(0.5, 2.5), temperature, ..Self::default() }
+    }
+
+    /// Short description of the corrections, for the status bar
+    pub fn summary(&self) -> String {
+        let Self { brightness, contrast, gamma, temperature } = self;
+        format!("B{brightness:+.2} C{contrast:.2} G{gamma:.2} T{temperature:+.2}")
+    }
+}
+
+fn luminance([r, g, b]: [f32; 3]) -> f32 { 0.299 * r + 0.587 * g + 0.114 * b }
+
+/// Average colour, as fractions of full scale, of the middle of a crop, where
+/// the face should be.
+pub fn face_colour(crop: &RgbImage) -> [f32; 3] {
+    let (w, h) = crop.dimensions();
+    let (x0, x1) = (w * 3 / 10, (w * 7 / 10).max(w * 3 / 10 + 1));
+    let (y0, y1) = (h * 3 / 10, (h * 6 / 10).max(h * 3 / 10 + 1));
+    let mut sum = [0.0; 3];
+    let mut n = 0.0;
+    for y in y0..y1.min(h) {
+        for x in x0..x1.min(w) {
+            for (s, c) in sum.iter_mut().zip(crop.get_pixel(x, y).0) { *s += c as f32 / 255.0; }
+            n += 1.0;
+        }
+    }
+    sum.map(|s| s / f32::max(n, 1.0))
+}
+
+/// Corrections which bring the faces, whose average colours are given, in line
+/// with the typical face of the class.
+pub fn normalise(colours: &[[f32; 3]]) -> Vec<Adjustments> {
+    let median = |channel: usize| {
+        let mut values = colours.iter().map(|c| c[channel]).collect::<Vec<_>>();
+        values.sort_by(f32::total_cmp);
+        values.get(values.len() / 2).copied().unwrap_or(0.5)
+    };
+    let target = [median(0), median(1), median(2)];
+    colours.iter().map(|&colour| Adjustments::matching(colour, target)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn neutral_changes_nothing() {
+        let original = RgbImage::from_fn(16, 16, |x, y| [(x * 16) as u8, (y * 16) as u8, 128].into());
+        let mut image = original.clone();
+        let mut adjustments = Adjustments::default();
+        adjustments.nudge(Setting::Gamma,  3.0);
+        adjustments.nudge(Setting::Gamma, -3.0);
+        adjustments.apply(&mut image);
+        assert!(adjustments.is_neutral());
+        assert_eq!(image, original);
+    }
+
+    #[test]
+    fn normalising_brings_faces_together() {
+        let colours = [[0.2, 0.15, 0.1], [0.5, 0.4, 0.3], [0.65, 0.5, 0.45]];
+        let adjustments = normalise(&colours);
+        assert!(adjustments[1].gamma.eq(&1.0) && adjustments[1].temperature.abs() < 1e-6);
+        let corrected = |n: usize| {
+            let mut image = RgbImage::from_pixel(1, 1, colours[n].map(|c| (c * 255.0) as u8).into());
+            adjustments[n].apply(&mut image);
+            face_colour(&image)
+        };
+        let lightness = |n: usize| luminance(corrected(n));
+        assert!((lightness(0) - lightness(1)).abs() < 0.05, "{} {}", lightness(0), lightness(1));
+        assert!((lightness(2) - lightness(1)).abs() < 0.05, "{} {}", lightness(2), lightness(1));
+    }
+
+    #[test]
+    fn warmer_is_redder() {
+        let mut image = RgbImage::from_pixel(1, 1, [100, 100, 100].into());
+        let mut adjustments = Adjustments::default();
+        adjustments.nudge(Setting::Temperature, 4.0);
+        adjustments.apply(&mut image);
+        let [r, g, b] = image.get_pixel(0, 0).0;
+        assert!(r > g && g > b, "{r} {g} {b}");
+    }
+}
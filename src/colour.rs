@@ -0,0 +1,258 @@
+//! Bringing photos of every colour type into 8-bit sRGB, which is what the
+//! cropper shows and the crops are written in.
+
+use std::io::Cursor;
+use std::path::Path;
+
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, ImageResult, RgbImage, RgbaImage};
+use img_parts::jpeg::{Jpeg, markers};
+use qcms::{DataType, Intent, Profile, Transform};
+use zune_core::{colorspace::ColorSpace, options::DecoderOptions};
+use zune_jpeg::JpegDecoder;
+
+/// Decode the photo read from `path`, of any colour type, honouring its
+/// embedded ICC profile, if it has one.
+pub fn decode(bytes: &[u8], path: &Path) -> ImageResult<RgbImage> {
+    let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+    if reader.format() == Some(ImageFormat::Jpeg) {
+        let icc = jpeg_icc_profile(bytes);
+        if let Some(icc) = icc.as_deref().filter(|&icc| colour_space(icc) == CMYK) {
+            if let Some(image) = decode_cmyk_jpeg(bytes, icc) { return Ok(image) }
+        }
+        return Ok(to_srgb(reader.decode()?, icc.as_deref(), path))
+    }
+    let mut decoder = reader.into_decoder()?;
+    let icc = decoder.icc_profile()?;
+    Ok(to_srgb(DynamicImage::from_decoder(decoder)?, icc.as_deref(), path))
+}
+
+const RGB : &[u8] = b"RGB ";
+const GRAY: &[u8] = b"GRAY";
+const CMYK: &[u8] = b"CMYK";
+
+/// The colour space signature in the header of an ICC profile
+fn colour_space(icc: &[u8]) -> &[u8] { icc.get(16..20).unwrap_or_default() }
+
+/// Convert `image`, read from `path`, whose colours are described by the ICC
+/// profile `icc`, to 8-bit sRGB. Without a profile, the colours are taken to be
+/// sRGB already. Transparent areas become white.
+pub fn to_srgb(image: DynamicImage, icc: Option<&[u8]>, path: &Path) -> RgbImage {
+    let srgb = Profile::new_sRGB();
+    let profile = icc
+        .and_then(|icc| Profile::new_from_slice(icc, false))
+        .filter(|profile| !profile.is_sRGB());
+    let Some(profile) = profile else {
+        return if image.color().has_alpha() { flatten(&image.into_rgba8()) } else { image.into_rgb8() }
+    };
+    let grey = image.color().channel_count() <= 2;
+    let transform = |from, to| Transform::new_to(&profile, &srgb, from, to, Intent::Perceptual);
+    let (w, h) = (image.width(), image.height());
+    match (colour_space(icc.unwrap()), grey) {
+        (GRAY, true) => if let Some(transform) = transform(DataType::GrayA8, DataType::RGBA8) {
+            let mut rgba = RgbaImage::new(w, h);
+            transform.convert(&image.into_luma_alpha8(), &mut rgba);
+            return flatten(&rgba)
+        },
+        (RGB, false) => if let Some(transform) = transform(DataType::RGBA8, DataType::RGBA8) {
+            let mut rgba = image.into_rgba8();
+            transform.apply(&mut rgba);
+            return flatten(&rgba)
+        },
+        _ => {},
+    }
+    println!("WARNING: ignoring the colour profile of {}, which does not match the image", path.display());
+    to_srgb(image, None, path)
+}
+
+/// `image` looks for the profile in JPEGs strictly, and so trips over our own
+/// metadata segment.
+fn jpeg_icc_profile(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut decoder = JpegDecoder::new_with_options(bytes, lenient());
+    decoder.decode_headers().ok()?;
+    decoder.icc_profile()
+}
+
+fn lenient() -> DecoderOptions { DecoderOptions::default().set_strict_mode(false) }
+
+/// Decode a CMYK JPEG and convert it through its ICC profile. Returns `None`
+/// if the JPEG's samples are not plain CMYK, so that it can be decoded the
+/// usual way, with an approximate conversion.
+fn decode_cmyk_jpeg(bytes: &[u8], icc: &[u8]) -> Option<RgbImage> {
+    let options = lenient().jpeg_set_out_colorspace(ColorSpace::CMYK);
+    let mut decoder = JpegDecoder::new_with_options(bytes, options);
+    decoder.decode_headers().ok()?;
+    if decoder.get_input_colorspace()? != ColorSpace::CMYK { return None }
+    let (w, h) = decoder.dimensions()?;
+    let mut cmyk = decoder.decode().ok()?;
+    // Adobe, which writes nearly all CMYK JPEGs, stores the ink inverted, and
+    // marks the file as its own; others store it as is
+    if has_adobe_marker(bytes) {
+        for ink in &mut cmyk { *ink = 255 - *ink; }
+    }
+    let profile = Profile::new_from_slice(icc, false)?;
+    let transform = Transform::new_to(&profile, &Profile::new_sRGB(), DataType::CMYK, DataType::RGB8, Intent::Perceptual)?;
+    let mut rgb = RgbImage::new(w as u32, h as u32);
+    transform.convert(&cmyk, &mut rgb);
+    Some(rgb)
+}
+
+fn has_adobe_marker(bytes: &[u8]) -> bool {
+    let Ok(jpeg) = Jpeg::from_bytes(bytes.to_owned().into()) else { return false };
+    jpeg.segments().iter().any(|s| s.marker() == markers::APP14 && s.contents().starts_with(b"Adobe"))
+}
+
+/// Composite `image` over a white background
+fn flatten(image: &RgbaImage) -> RgbImage {
+    let over_white = |c: u8, a: u8| ((c as u32 * a as u32 + 255 * (255 - a as u32) + 127) / 255) as u8;
+    RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        let [r, g, b, a] = image.get_pixel(x, y).0;
+        [over_white(r, a), over_white(g, a), over_white(b, a)].into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Luma, LumaA, Rgb, Rgba};
+    use rstest::rstest;
+    use pretty_assertions::assert_eq;
+
+    #[rstest]
+    #[case::grey        (DynamicImage::ImageLuma8 (ImageBuffer::from_pixel(1, 1, Luma([100]))),                 [100, 100, 100])]
+    #[case::grey_16     (DynamicImage::ImageLuma16(ImageBuffer::from_pixel(1, 1, Luma([100 * 257]))),           [100, 100, 100])]
+    #[case::rgb_16      (DynamicImage::ImageRgb16 (ImageBuffer::from_pixel(1, 1, Rgb([0, 257 * 128, 65535]))),  [  0, 128, 255])]
+    #[case::opaque      (DynamicImage::ImageRgba8 (ImageBuffer::from_pixel(1, 1, Rgba([10, 20, 30, 255]))),     [ 10,  20,  30])]
+    #[case::transparent (DynamicImage::ImageRgba8 (ImageBuffer::from_pixel(1, 1, Rgba([10, 20, 30, 0]))),       [255, 255, 255])]
+    #[case::half_grey   (DynamicImage::ImageLumaA8(ImageBuffer::from_pixel(1, 1, LumaA([0, 128]))),             [127, 127, 127])]
+    fn every_colour_type_becomes_rgb8(#[case] image: DynamicImage, #[case] expected: [u8; 3]) {
+        assert_eq!(to_srgb(image, None, Path::new("test")).get_pixel(0, 0).0, expected);
+    }
+
+    /// ICC profile with the sRGB primaries, but no gamma: its midtones are
+    /// much lighter once in sRGB.
+    fn linear_rgb_profile() -> Vec<u8> {
+        let xyz = |xyz: [f64; 3]| {
+            let mut tag = b"XYZ \0\0\0\0".to_vec();
+            for v in xyz { tag.extend(((v * 65536.0).round() as i32).to_be_bytes()); }
+            tag
+        };
+        let white = [0.9642, 1.0, 0.8249];
+        let linear = b"curv\0\0\0\0\0\0\0\x01\x01\0\0\0".to_vec();
+        let tags = [
+            (b"rXYZ", xyz([0.4361, 0.2225, 0.0139])),
+            (b"gXYZ", xyz([0.3851, 0.7169, 0.0971])),
+            (b"bXYZ", xyz([0.1431, 0.0606, 0.7141])),
+            (b"wtpt", xyz(white)),
+            (b"rTRC", linear.clone()),
+            (b"gTRC", linear.clone()),
+            (b"bTRC", linear),
+        ];
+        let mut header = vec![0; 128];
+        header[ 8..12].copy_from_slice(&[2, 0x10, 0, 0]);
+        header[12..16].copy_from_slice(b"mntr");
+        header[16..20].copy_from_slice(b"RGB ");
+        header[20..24].copy_from_slice(b"XYZ ");
+        header[36..40].copy_from_slice(b"acsp");
+        header[68..80].copy_from_slice(&xyz(white)[8..]);
+        let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+        let mut data = vec![];
+        let data_start = header.len() + 4 + 12 * tags.len();
+        for (signature, tag) in tags {
+            table.extend(signature);
+            table.extend(((data_start + data.len()) as u32).to_be_bytes());
+            table.extend((tag.len() as u32).to_be_bytes());
+            data.extend(tag);
+        }
+        let mut profile = [header, table, data].concat();
+        let size = (profile.len() as u32).to_be_bytes();
+        profile[..4].copy_from_slice(&size);
+        profile
+    }
+
+    #[rstest]
+    #[case::png (ImageFormat::Png )]
+    #[case::jpeg(ImageFormat::Jpeg)]
+    fn embedded_profile_is_honoured(#[case] format: ImageFormat) {
+        use img_parts::{DynImage, ImageICC};
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([128, 128, 128])))
+            .write_to(&mut Cursor::new(&mut bytes), format).unwrap();
+        let mut image = DynImage::from_bytes(bytes.into()).unwrap().unwrap();
+        image.set_icc_profile(Some(linear_rgb_profile().into()));
+        let mut bytes = Vec::new();
+        image.encoder().write_to(&mut bytes).unwrap();
+        let [r, g, b] = decode(&bytes, Path::new("test")).unwrap().get_pixel(4, 4).0;
+        // 50% of the light is 188 in sRGB
+        assert!([r, g, b].iter().all(|&c| c.abs_diff(188) <= 2), "{r} {g} {b}");
+    }
+
+    /// An 8 by 8 CMYK JPEG of uniform `ink`, stored inverted with an Adobe
+    /// marker, as Adobe does, or as is, with the generic CMYK profile.
+    fn cmyk_jpeg(ink: [u8; 4], adobe: bool) -> Vec<u8> {
+        let segment = |marker: u8, contents: &[u8]| {
+            [&[0xFF, marker][..], &(contents.len() as u16 + 2).to_be_bytes(), contents].concat()
+        };
+        let mut jpeg = vec![0xFF, markers::SOI];
+        if adobe { jpeg.extend(segment(markers::APP14, b"Adobe\0\x64\0\0\0\0\0")); }
+        let icc = typst_assets::icc::CMYK_TO_XYZ;
+        jpeg.extend(segment(markers::APP2, &[b"ICC_PROFILE\0\x01\x01", icc].concat()));
+        jpeg.extend(segment(markers::DQT, &[[0].as_slice(), &[1; 64]].concat()));
+        let components = (1..=4).flat_map(|id| [id, 0x11, 0]).collect::<Vec<_>>();
+        jpeg.extend(segment(markers::SOF0, &[[8, 0, 8, 0, 8, 4].as_slice(), &components].concat()));
+        // DC differences of any size, with 4 bit codes; AC has only end of block, code 0
+        let mut dc_lengths = [0; 16];
+        dc_lengths[3] = 12;
+        jpeg.extend(segment(markers::DHT, &[[0x00].as_slice(), &dc_lengths, &(0..12).collect::<Vec<u8>>()].concat()));
+        let mut ac_lengths = [0; 16];
+        ac_lengths[0] = 1;
+        jpeg.extend(segment(markers::DHT, &[[0x10].as_slice(), &ac_lengths, &[0x00]].concat()));
+        let scan = (1..=4).flat_map(|id| [id, 0x00]).collect::<Vec<_>>();
+        jpeg.extend(segment(markers::SOS, &[[4].as_slice(), &scan, &[0, 63, 0]].concat()));
+        let mut bits = vec![];
+        let mut put = |value: i32, n: u32| bits.extend((0..n).rev().map(|i| (value >> i) & 1 == 1));
+        for ink in ink {
+            let sample = if adobe { 255 - ink } else { ink };
+            // Sole coefficient of a uniform block
+            let dc = 8 * (sample as i32 - 128);
+            let size = 32 - dc.unsigned_abs().leading_zeros();
+            put(size as i32, 4);
+            put(if dc < 0 { dc - 1 } else { dc }, size);
+            put(0, 1);
+        }
+        bits.resize(bits.len().div_ceil(8) * 8, true);
+        for byte in bits.chunks(8).map(|bits| bits.iter().fold(0u8, |byte, &bit| byte << 1 | bit as u8)) {
+            jpeg.push(byte);
+            if byte == 0xFF { jpeg.push(0); }
+        }
+        jpeg.extend([0xFF, markers::EOI]);
+        jpeg
+    }
+
+    #[rstest]
+    #[case::adobe (true )]
+    #[case::as_is (false)]
+    fn cmyk_jpegs_are_converted_through_their_profile(#[case] adobe: bool) {
+        let colour = |ink| decode(&cmyk_jpeg(ink, adobe), Path::new("test")).unwrap().get_pixel(4, 4).0;
+        let [r, g, b] = colour([0, 0, 0, 0]);
+        assert!(r > 220 && g > 220 && b > 220, "paper {r} {g} {b}");
+        let [r, g, b] = colour([0, 0, 0, 255]);
+        assert!(r < 60 && g < 60 && b < 60, "black {r} {g} {b}");
+        let [r, g, b] = colour([255, 0, 0, 0]);
+        assert!(r < 100 && g > 100 && b > 180, "cyan {r} {g} {b}");
+    }
+
+    #[test]
+    fn jpeg_with_our_metadata_decodes() {
+        use img_parts::{Bytes, jpeg::JpegSegment};
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 8, Rgb([200, 100, 50])))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Jpeg).unwrap();
+        let mut jpeg = Jpeg::from_bytes(bytes.into()).unwrap();
+        let ours = Bytes::from_static(b"trombinoscope, not Adobe");
+        jpeg.segments_mut().insert(1, JpegSegment::new_with_contents(markers::APP14, ours));
+        let mut bytes = Vec::new();
+        jpeg.encoder().write_to(&mut bytes).unwrap();
+        let [r, g, b] = decode(&bytes, Path::new("test")).unwrap().get_pixel(4, 4).0;
+        assert!(r > g && g > b, "{r} {g} {b}");
+    }
+}
@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::crop::{AspectRatio, Output};
+use crate::metadata::Storage;
+
+/// Name of the per-class settings file, in the class directory
+pub const CONFIG_FILE: &str = "trombinoscope.toml";
+
+/// Settings which may differ from one class to another.
+///
+/// Every setting is optional: a class without a `trombinoscope.toml` gets the
+/// defaults.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ClassConfig {
+    /// Where crop boxes and names are kept
+    pub storage: Storage,
+    /// Only this many photos are used, in order of file name
+    pub max_photos: Option<usize>,
+    /// Shape of the crops: "2:3", "4:5" or "square"
+    pub aspect_ratio: AspectRatio,
+    /// How the cropped images used in the PDFs are written: the `[output]` table
+    pub output: Output,
+}
+
+fn config_path(class_dir: impl AsRef<Path>) -> PathBuf { class_dir.as_ref().join(CONFIG_FILE) }
+
+impl ClassConfig {
+    pub fn load(class_dir: impl AsRef<Path>) -> Self {
+        let path = config_path(class_dir);
+        let Ok(text) = std::fs::read_to_string(&path) else { return Self::default() };
+        Self::parse(&text)
+            .unwrap_or_else(|err| panic!("Error in {}:\n{err}", path.display()))
+    }
+
+    /// Leave out the sorted `photos` beyond `max_photos`, if it is set
+    pub fn limit_photos(&self, photos: &mut Vec<PathBuf>) {
+        let Some(max) = self.max_photos.filter(|&max| photos.len() > max) else { return };
+        println!("WARNING: only using the first {max} photos, as set by `max-photos` in {CONFIG_FILE}. Ignoring:");
+        for path in &photos[max..] {
+            println!("    {}", path.display());
+        }
+        photos.truncate(max);
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let config: Self = toml::from_str(text).map_err(|err| err.to_string())?;
+        config.output.check()?;
+        Ok(config)
+    }
+
+    /// Record `storage` in the class' settings file, preserving anything else
+    /// that might be in it.
+    pub fn set_storage(class_dir: impl AsRef<Path>, storage: Storage) {
+        let path = config_path(class_dir);
+        let text = std::fs::read_to_string(&path).unwrap_or_default();
+        let mut doc: toml_edit::DocumentMut = text
+            .parse()
+            .unwrap_or_else(|err| panic!("Error in {}:\n{err}", path.display()));
+        let toml::Value::String(storage) = toml::Value::try_from(storage).unwrap()
+            else { unreachable!("Storage is serialized as a string") };
+        doc["storage"] = toml_edit::value(storage);
+        std::fs::write(&path, doc.to_string()).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn every_photo_is_used_unless_limited() {
+        let photos = ["a.jpg", "b.jpg", "c.jpg"].map(PathBuf::from);
+        let used = |text: &str| {
+            let mut used = photos.to_vec();
+            ClassConfig::parse(text).unwrap().limit_photos(&mut used);
+            used
+        };
+        assert_eq!(used(""), photos);
+        assert_eq!(used("max-photos = 5"), photos);
+        assert_eq!(used("max-photos = 2"), photos[..2]);
+    }
+
+    #[test]
+    fn output_settings_which_cannot_be_used_are_errors() {
+        let output = |table: &str| ClassConfig::parse(&format!("[output]\n{table}")).map(|config| config.output);
+        assert_eq!(output("quality = 100\nwidth = 1\ndpi = 1"), Ok(Output { quality: 100, width: Some(1), dpi: Some(1), ..Output::default() }));
+        for table in ["quality = 0", "quality = 101", "width = 0", "dpi = 0"] {
+            assert!(output(table).is_err(), "{table}");
+        }
+    }
+}
@@ -1,219 +1,1346 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
 use std::fs::File;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::io::Write;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-use image::{DynamicImage, GenericImageView, codecs::jpeg::JpegEncoder};
-use img_parts::jpeg::{self, JpegSegment, Jpeg};
+use image::{DynamicImage, ImageFormat, RgbImage, codecs::jpeg::JpegEncoder, imageops};
+use serde::Deserialize;
 use show_image::event;
-use bitcode::{self, Encode, Decode};
+use siphasher::sip::SipHasher13;
 
+use crate::adjust::{self, Adjustments, Setting};
+use crate::display::{self, Guides, Rect};
+use crate::face::{self, Face};
+use crate::history::History;
+use crate::keymap::{Action, Keymap};
+use crate::loader::{self, Job, Keep, Loaded, Loader};
+use crate::metadata::{self, CropBox, Metadata, MetadataStore};
+use crate::orientation::Orientation;
 use crate::util::filename_to_given_family;
 
 
-#[derive(Encode, Decode, PartialEq, Debug)]
-struct Metadata {
-    given: String,
-    family: String,
-    x: i32,
-    y: i32,
-    w: i32,
-}
-
 #[derive(Debug)]
 pub struct Cropped {
     pub path: PathBuf,
-    image: DynamicImage,
+    /// Reduced copy of the photo the right way up, for display, when it is in
+    /// memory. The crop is always given in the coordinates of the full size
+    /// photo.
+    proxy: Option<DynamicImage>,
+    /// Size of the full size photo, the right way up
+    size: (u32, u32),
     pub given: String,
     pub family: String,
-    x: i32,
-    y: i32,
-    w: i32,
+    /// Centre and width of the crop, in pixels of the full size photo, the
+    /// right way up
+    x: f64,
+    y: f64,
+    w: f64,
     /// height to width aspect ratio
     r: (i32, i32),
+    /// Orientation recorded by the camera
+    exif_orientation: Orientation,
+    /// Manual correction, which takes precedence over `exif_orientation`
+    orientation: Option<Orientation>,
+    /// Whether the crop was loaded from storage or chosen by someone, rather
+    /// than guessed
+    stored: bool,
+    /// What the store holds for this photo, so that it is only written when
+    /// something has changed
+    saved: Option<Metadata>,
+    /// The crop is a rough guess, to be replaced once a face has been looked for
+    awaiting_face: bool,
+    /// Whether someone has confirmed the crop
+    reviewed: bool,
+    /// Left out of the generated images and PDFs
+    excluded: bool,
+    /// Exposure and colour corrections chosen for this photo
+    adjustments: Option<Adjustments>,
+    /// Corrections found by `normalise_exposure`, used unless `adjustments` is set
+    default_adjustments: Option<Adjustments>,
+    /// Another photo differs from this one only in its extension, so the crop
+    /// keeps this one's extension in its name (see `output_name`)
+    output_keeps_extension: bool,
+}
+
+/// The part of a `Cropped` which can be undone
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct CropState {
+    x: f64,
+    y: f64,
+    w: f64,
+    orientation: Option<Orientation>,
+    adjustments: Option<Adjustments>,
+}
+
+/// The first face needing review after the `current` one, wrapping around
+fn next_unreviewed(faces: &[Cropped], current: usize) -> Option<usize> {
+    (1..=faces.len())
+        .map(|offset| (current + offset) % faces.len())
+        .find(|&n| faces[n].needs_review())
+}
+
+/// Names being typed in, while the cropper is in name-editing mode
+struct NameEntry {
+    given: String,
+    family: String,
+    editing_family: bool,
+    /// The key which started editing, until it is released: the character
+    /// it types, if any, is not part of the names
+    opening_key: Option<event::VirtualKeyCode>,
+}
+
+impl NameEntry {
+    fn new(face: &Cropped, opening_key: event::VirtualKeyCode) -> Self {
+        Self { given: face.given.clone(), family: face.family.clone(), editing_family: false, opening_key: Some(opening_key) }
+    }
+
+    fn field(&mut self) -> &mut String {
+        if self.editing_family { &mut self.family } else { &mut self.given }
+    }
+
+    fn status(&self) -> String {
+        let cursor = |on| if on { "_" } else { "" };
+        format!("Given: {}{}   Family: {}{}   [Tab: switch, Return: save, Escape: cancel]",
+                self.given , cursor(!self.editing_family),
+                self.family, cursor( self.editing_family),
+        )
+    }
+}
+
+/// What the cropper shows
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+enum View {
+    /// Just the cropped part of the image
+    #[default]
+    Crop,
+    /// The whole image, with the crop outlined
+    Full,
 }
 
-fn bytes_to_jpeg(bytes: &[u8]) -> Jpeg { Jpeg::from_bytes(bytes.to_owned().into()).unwrap() }
-fn write_jpeg(jpeg: Jpeg, sink: &mut impl Write) { jpeg.encoder().write_to(sink).unwrap(); }
-fn read_jpeg(path: impl AsRef<Path>) -> Jpeg { bytes_to_jpeg(&std::fs::read(&path).unwrap()) }
+impl View {
+    fn toggle(self) -> Self { match self { View::Crop => View::Full, View::Full => View::Crop } }
+}
 
-const OUR_MARKER: u8 = jpeg::markers::APP14;
-const OUR_LABEL: &str = "trombinoscope";
+/// Width of a crop seeded by face detection, relative to the width of the face
+const CROP_PER_FACE_WIDTH: f64 = 2.2;
+/// Fraction of the crop height between its top edge and the centre of the face
+const HEADROOM: f64 = 0.42;
+/// Width of the smallest crop, relative to the width of the photo: any smaller
+/// and there is too little left to see, or to print
+const MIN_CROP_FRACTION: f64 = 0.02;
 
 impl Cropped {
-    fn new(path: impl AsRef<Path>, image: DynamicImage) -> Self {
-        let (w, h) = image.dimensions();
+    fn new(path: impl AsRef<Path>, size: (u32, u32), exif_orientation: Orientation, r: (i32, i32)) -> Self {
         let basename = path.as_ref().file_name().unwrap();
         let (given, family) = filename_to_given_family(basename).unwrap();
-        Self {
+        let mut new = Self {
             path: path.as_ref().into(),
-            image,
+            proxy: None,
+            size,
             given,
             family,
-            x: w as i32 / 2,
-            y: h as i32 / 5,
-            w: w as i32 / 5,
-            r: (3, 2),
-        }
+            x: 0.0,
+            y: 0.0,
+            w: 0.0,
+            r,
+            exif_orientation,
+            orientation: None,
+            stored: false,
+            saved: None,
+            awaiting_face: false,
+            reviewed: false,
+            excluded: false,
+            adjustments: None,
+            default_adjustments: None,
+            output_keeps_extension: false,
+        };
+        new.default_crop();
+        new
+    }
+
+    /// Rough guess at where the face might be, when nothing better is known
+    fn default_crop(&mut self) {
+        let (w, h) = self.size;
+        self.x = w as f64 / 2.0;
+        self.y = h as f64 / 5.0;
+        self.w = w as f64 / 5.0;
     }
 
-    fn set_metadata(&mut self, Metadata { given, family, x, y, w }: Metadata) {
+    fn set_metadata(&mut self, metadata: Metadata) {
+        self.saved = Some(metadata.clone());
+        let Metadata { given, family, crop, r, orientation, reviewed, excluded, adjustments, default_adjustments } = metadata;
         self.given  = given;
         self.family = family;
-        self.x = x;
-        self.y = y;
-        self.w = w;
+        // The size is that of the photo as it is now, which may have been
+        // resized since it was cropped
+        (self.x, self.y, self.w) = crop.in_pixels(self.size);
+        self.orientation = orientation;
+        self.reviewed = reviewed;
+        self.excluded = excluded;
+        self.adjustments = adjustments;
+        self.default_adjustments = default_adjustments;
+        self.stored = true;
+        if r != self.r {
+            // Keeping the centre and width is the best that can be done
+            // without looking at the photo, so someone should
+            println!("WARNING: {} was cropped to a different aspect ratio, its crop needs to be reviewed",
+                     self.path.display());
+            self.reviewed = false;
+            let &mut Self { x, y, w, .. } = self;
+            self.xxx(x, y, w);
+        }
     }
 
-    pub fn load(path: impl AsRef<Path>) -> Option<Cropped> {
-        let start = Instant::now();
-        let image = image::open(&path).ok()?;
-        let elapsed = start.elapsed();
-        let image = image.rotate270();
-        println!("Loaded {path} in {elapsed:.0?}", path = path.as_ref().display());
-
-        let mut new = Self::new(&path, image);
-        let jpeg = read_jpeg(&path);
-
-        // TODO, use OUR_LABEL to avoid collisions with other apps using OUR_MARKER
-        let metadata = jpeg
-            .segment_by_marker(OUR_MARKER)
-            .map(|seg| {
-                let c = seg.contents().to_vec();
-                bitcode::decode(&c).unwrap()
-            });
+    /// Whether someone has ever cropped this image, as opposed to the crop
+    /// having been guessed
+    pub fn has_been_cropped(&self) -> bool { self.stored }
+
+    /// Whether this photo should be left out of the outputs
+    pub fn is_excluded(&self) -> bool { self.excluded }
+
+    /// Whether this photo still needs someone to look at it
+    fn needs_review(&self) -> bool { !self.reviewed && !self.excluded }
+
+    /// Read what is known about the photo at `path`, from the beginning of its
+    /// file only: decoding the image is left to a `Loader`.
+    pub fn load(path: impl AsRef<Path>, store: &dyn MetadataStore, ratio: AspectRatio) -> Option<Cropped> {
+        let header = metadata::read_header(&path).ok()?;
+        let size = metadata::image_size(&header)?;
+
+        let metadata = store.load(path.as_ref());
+
+        let exif_orientation = Orientation::read_exif(&header).unwrap_or_default();
+        let orientation = metadata.as_ref().and_then(|m| m.orientation).unwrap_or(exif_orientation);
+        let mut new = Self::new(&path, orientation.apply_to_size(size), exif_orientation, ratio.height_to_width());
+
         if let Some(metadata) = metadata {
             new.set_metadata(metadata);
+        } else {
+            new.awaiting_face = true;
         };
         Some(new)
     }
 
-    fn save_metadata(&self) {
-        let mut jpeg = read_jpeg(&self.path);
-        let all_segments = jpeg.segments_mut();
-        let new_segment = self.make_metadata_segment();
-        if let Some(segment) = all_segments.iter_mut().find(|seg| seg.marker() == OUR_MARKER) {
-            *segment = new_segment;
-        } else {
-            let new_pos = all_segments.len() - 1;
-            all_segments.insert(new_pos, new_segment);
+    fn current_orientation(&self) -> Orientation { self.orientation.unwrap_or(self.exif_orientation) }
+
+    /// Work needed to bring this face up to date: decoding the image, and
+    /// finding the face if that has not been done yet.
+    fn job(&self, n: usize, keep: Keep) -> Job {
+        Job {
+            n,
+            path: self.path.clone(),
+            orientation: self.current_orientation(),
+            size: self.size,
+            detect: self.awaiting_face,
+            keep,
+        }
+    }
+
+    /// Take delivery of the outcome of `job`
+    fn receive(&mut self, Loaded { orientation, image, face, .. }: Loaded) {
+        // Only faces in memory are ever turned, so this should not happen;
+        // if it does, the result is stale and the face will be requested again.
+        if orientation != self.current_orientation() { return }
+        if self.awaiting_face {
+            if let Some(face) = face { self.frame_face(face); }
+            self.awaiting_face = false;
+        }
+        if image.is_some() { self.proxy = image; }
+    }
+
+    pub fn is_loaded(&self) -> bool { self.proxy.is_some() }
+    fn unload(&mut self) { self.proxy = None; }
+
+    fn proxy(&self) -> &DynamicImage {
+        self.proxy.as_ref().unwrap_or_else(|| panic!("{} has not been loaded", self.path.display()))
+    }
+
+    /// Size of the full size photo, relative to the proxy
+    fn proxy_scale(&self) -> f32 { self.size.0 as f32 / self.proxy().width() as f32 }
+
+    /// The photo at full size, the right way up
+    fn full_image(&self) -> DynamicImage {
+        loader::decode(&self.path, self.current_orientation())
+            .unwrap_or_else(|err| panic!("Could not decode {}: {err}", self.path.display()))
+    }
+
+    /// Place the crop box around the most prominent face, leaving the default
+    /// guess in place if no face can be found.
+    fn centre_on_face(&mut self) {
+        let start = Instant::now();
+        let Some(face) = face::detect(self.proxy()) else {
+            println!("No face found in {}", self.path.display());
+            return;
         };
-        let file = &mut File::create(&self.path).unwrap();
-        write_jpeg(jpeg, file);
+        println!("Found face in {} in {:.0?}", self.path.display(), start.elapsed());
+        let scale = self.proxy_scale();
+        let [x, y, w, h] = [face.x, face.y, face.w, face.h].map(|n| (n as f32 * scale) as i32);
+        self.frame_face(Face { x, y, w, h });
+    }
+
+    fn frame_face(&mut self, face: Face) {
+        let w = (face.w as f64 * CROP_PER_FACE_WIDTH).min(self.max_crop_w());
+        let h = self.h_for(w);
+        let y = face.y as f64 + h * (0.5 - HEADROOM);
+        self.xxx(face.x as f64, y, w);
     }
 
-    fn make_metadata_segment(&self) -> JpegSegment {
-        let &Self { x, y, w, .. } = self;
-        let metadata = Metadata {
+    /// Turn the image a quarter turn clockwise, overriding the EXIF orientation.
+    ///
+    /// The old crop box is meaningless in the turned image, so a new one is found.
+    fn rotate_clockwise(&mut self) {
+        self.turn();
+        self.default_crop();
+        self.centre_on_face();
+    }
+
+    /// Turn the image, and the orientation with which it is displayed, a
+    /// quarter turn clockwise.
+    fn turn(&mut self) {
+        self.orientation = Some(self.current_orientation().rotated_clockwise());
+        self.proxy = self.proxy.as_ref().map(DynamicImage::rotate90);
+        self.size = (self.size.1, self.size.0);
+    }
+
+    /// What the cropper displays for this face
+    fn show(&self, view: View, guides: Guides) -> RgbImage {
+        match view {
+            View::Crop => {
+                let mut image = self.get().to_rgb8();
+                self.adjustments().apply(&mut image);
+                let (w, h) = image.dimensions();
+                let rect = Rect { x: 0, y: 0, w: w as i32, h: h as i32 };
+                display::guides(&mut image, guides, rect, (w as i32 / 200).max(1));
+                image
+            },
+            View::Full => {
+                let mut image = self.proxy().to_rgb8();
+                self.adjustments().apply(&mut image);
+                let scale = 1.0 / self.proxy_scale();
+                let Region { x, y, w, h } = self.region();
+                let [x, y, w, h] = [x, y, w, h].map(|n| (n * scale).round() as i32);
+                let rect = Rect { x, y, w, h };
+                display::dim_outside(&mut image, rect);
+                display::guides(&mut image, guides, rect, 1);
+                display::outline(&mut image, rect, 2);
+                image
+            },
+        }
+    }
+
+    /// Name, crop size and review state, for the status bar
+    fn status(&self) -> String {
+        let reviewed = match (self.excluded, self.reviewed) {
+            (true, _    ) => "EXCLUDED",
+            (_   , true ) => "reviewed",
+            (_   , false) => "not reviewed",
+        };
+        let adjusted = match (self.adjustments, self.default_adjustments) {
+            (Some(manual), _         ) => format!("   {}", manual.summary()),
+            (None        , Some(auto)) => format!("   auto {}", auto.summary()),
+            (None        , None      ) => String::new(),
+        };
+        format!("{} {}   {:.0}×{:.0} px   {reviewed}{adjusted}", self.given, self.family, self.w, self.h())
+    }
+
+    /// The corrections to be applied to this photo
+    fn adjustments(&self) -> Adjustments {
+        self.adjustments.or(self.default_adjustments).unwrap_or_default()
+    }
+
+    fn adjust(&mut self, setting: Setting, steps: f32) {
+        let mut adjustments = self.adjustments();
+        adjustments.nudge(setting, steps);
+        self.adjustments = Some(adjustments);
+    }
+
+    fn state(&self) -> CropState {
+        let &Self { x, y, w, orientation, adjustments, .. } = self;
+        CropState { x, y, w, orientation, adjustments }
+    }
+
+    fn restore(&mut self, CropState { x, y, w, orientation, adjustments }: CropState) {
+        // Orientations only ever change by quarter turns, so the image can be
+        // turned back without reloading it
+        let target = orientation.unwrap_or(self.exif_orientation);
+        while self.current_orientation() != target { self.turn(); }
+        self.orientation = orientation;
+        self.adjustments = adjustments;
+        self.x = x;
+        self.y = y;
+        self.w = w;
+    }
+
+    /// Write the metadata to `store`, unless it holds them already. Someone
+    /// has just made a choice about this face, so its crop is no longer a guess.
+    fn save_metadata(&mut self, store: &dyn MetadataStore) {
+        self.stored = true;
+        save_changed_metadata([self], store);
+    }
+
+    /// The metadata, unless the store holds them already
+    fn unsaved_metadata(&self) -> Option<Metadata> {
+        let metadata = self.metadata();
+        (self.saved.as_ref() != Some(&metadata)).then_some(metadata)
+    }
+
+    fn metadata(&self) -> Metadata {
+        let &Self { x, y, w, r, size, orientation, reviewed, excluded, adjustments, default_adjustments, .. } = self;
+        Metadata {
             given : self.given .clone(),
             family: self.family.clone(),
-            x, y, w
-        };
-        let metadata = bitcode::encode(&metadata);
-        JpegSegment::new_with_contents(
-            OUR_MARKER,
-            img_parts::Bytes::copy_from_slice(&metadata)
-        )
+            crop: CropBox::from_pixels((x, y, w), size),
+            r,
+            orientation,
+            reviewed,
+            excluded,
+            adjustments,
+            default_adjustments,
+        }
     }
 
-    fn get(&self) -> DynamicImage {
-        let &Self { x, y, w, .. } = self;
-        let h = self.h();
-        self.image.crop_imm((x-w/2) as u32, (y-h/2) as u32, w as u32, h as u32)
+    fn get(&self) -> DynamicImage { self.crop_of(self.proxy()) }
+
+    /// The part of `image` inside the crop. `image` is the photo the right way
+    /// up, at full size or reduced.
+    fn crop_of(&self, image: &DynamicImage) -> DynamicImage {
+        let scale = image.width() as f32 / self.size.0 as f32;
+        let Region { x, y, w, h } = self.region();
+        let [x, y, w, h] = [x, y, w, h].map(|n| (n * scale).round() as u32);
+        image.crop_imm(x, y, w.max(1), h.max(1))
     }
 
     pub fn write(&self, path: impl AsRef<Path>) -> Result<(), image::ImageError> {
-        self.get().save(&path)
+        self.crop_of(&self.full_image()).save(&path)
+    }
+
+    fn h(&self) -> f64 { self.h_for(self.w) }
+    fn h_for(&self, w: f64) -> f64 { let (hh, ww) = self.r; w * hh as f64 / ww as f64 }
+    /// Width of the largest crop which fits in the image
+    fn max_crop_w(&self) -> f64 { self.max_w().min(self.max_h() / self.h_for(1.0)) }
+    /// The valid crop nearest to `(x, y, w)`: the width is reduced to what
+    /// fits, then the centre is moved just far enough to bring the box inside
+    /// the image.
+    fn clamped(&self, x: f64, y: f64, w: f64) -> (f64, f64, f64) {
+        let w = w.min(self.max_crop_w()).max(self.max_w() * MIN_CROP_FRACTION);
+        let h = self.h_for(w);
+        // Not `clamp`, which panics when rounding leaves the limits crossed
+        let x = x.max(w / 2.0).min(self.max_w() - w / 2.0);
+        let y = y.max(h / 2.0).min(self.max_h() - h / 2.0);
+        (x, y, w)
     }
+    fn xxx(&mut self, x: f64, y: f64, w: f64) { (self.x, self.y, self.w) = self.clamped(x, y, w); }
 
-    fn h(&self) -> i32 { let (hh, ww) = self.r; self.w * hh / ww }
-    fn within_simits(&self, x: i32, y: i32, w: i32) -> bool {
-        let h = self.h();
-        x - w / 2 >= 0              &&
-        y - h / 2 >= 0              &&
-        x + w / 2 <  self.max_w()   &&
-        y + h / 2 <  self.max_h()   &&
-        w > 0
+    fn up      (&mut self, n: f64) { let &mut Self {x, y, w, ..} = self; self.xxx(x  , y+n, w  ) }
+    fn down    (&mut self, n: f64) { let &mut Self {x, y, w, ..} = self; self.xxx(x  , y-n, w  ) }
+    fn left    (&mut self, n: f64) { let &mut Self {x, y, w, ..} = self; self.xxx(x+n, y  , w  ) }
+    fn right   (&mut self, n: f64) { let &mut Self {x, y, w, ..} = self; self.xxx(x-n, y  , w  ) }
+    fn zoom_in (&mut self, n: f64) { let &mut Self {x, y, w, ..} = self; self.xxx(x  , y  , w-n) }
+    fn zoom_out(&mut self, n: f64) { let &mut Self {x, y, w, ..} = self; self.xxx(x  , y  , w+n) }
+    fn move_by (&mut self, dx: f64, dy: f64) { let &mut Self {x, y, w, ..} = self; self.xxx(x+dx, y+dy, w) }
+    fn centre_on(&mut self, x: f64, y: f64) { let w = self.w; self.xxx(x, y, w) }
+
+    /// Scale the crop by `factor`, keeping the source point `(px, py)` where it
+    /// is in the crop.
+    fn zoom_around(&mut self, (px, py): (f32, f32), factor: f32) {
+        let (px, py, factor) = (px as f64, py as f64, factor as f64);
+        let Region { x: left, y: top, .. } = self.region();
+        let (left, top) = (left as f64, top as f64);
+        let (w, h) = (self.w * factor, self.h() * factor);
+        let left = px - (px - left) * factor;
+        let top  = py - (py - top ) * factor;
+        self.xxx(left + w / 2.0, top + h / 2.0, w);
+    }
+
+    /// The part of the source image which is displayed in `view`
+    fn region_shown(&self, view: View) -> Region {
+        match view {
+            View::Crop => self.region(),
+            View::Full => Region { x: 0.0, y: 0.0, w: self.max_w() as f32, h: self.max_h() as f32 },
+        }
     }
-    fn xxx(&mut self, x: i32, y: i32, w: i32) { if self.within_simits(x, y, w) { self.x = x; self.y = y; self.w = w } }
 
-    fn up      (&mut self, n: i32) { let &mut Self {x, y, w, ..} = self; self.xxx(x  , y+n, w  ) }
-    fn down    (&mut self, n: i32) { let &mut Self {x, y, w, ..} = self; self.xxx(x  , y-n, w  ) }
-    fn left    (&mut self, n: i32) { let &mut Self {x, y, w, ..} = self; self.xxx(x+n, y  , w  ) }
-    fn right   (&mut self, n: i32) { let &mut Self {x, y, w, ..} = self; self.xxx(x-n, y  , w  ) }
-    fn zoom_in (&mut self, n: i32) { let &mut Self {x, y, w, ..} = self; self.xxx(x  , y  , w-n) }
-    fn zoom_out(&mut self, n: i32) { let &mut Self {x, y, w, ..} = self; self.xxx(x  , y  , w+n) }
-    fn max_h(&self) -> i32 { self.image.height() as i32 }
-    fn max_w(&self) -> i32 { self.image.width () as i32 }
+    /// The part of the source image inside the crop
+    fn region(&self) -> Region {
+        let (w, h) = (self.w, self.h());
+        let [x, y, w, h] = [self.x - w / 2.0, self.y - h / 2.0, w, h].map(|n| n as f32);
+        Region { x, y, w, h }
+    }
+
+    fn max_h(&self) -> f64 { self.size.1 as f64 }
+    fn max_w(&self) -> f64 { self.size.0 as f64 }
+}
+
+/// What caused a change, so that a burst of similar changes can be undone in one go
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Trigger {
+    Key(event::VirtualKeyCode),
+    Drag,
+    Wheel,
+    DoubleClick,
+}
+
+/// Second click must follow the first within this time, to count as a double-click
+const DOUBLE_CLICK_TIME: Duration = Duration::from_millis(400);
+/// Fraction by which the crop width changes with each notch of the mouse wheel
+const WHEEL_ZOOM_STEP: f32 = 0.05;
+
+/// Maps window positions to the image displayed in it, which is scaled to fit
+/// the window and centred in it.
+struct Viewport { window_w: f32, window_h: f32 }
+
+impl Viewport {
+    /// Number of window pixels per pixel of `region`
+    fn scale(&self, region: Region) -> f32 {
+        (self.window_w / region.w).min(self.window_h / region.h)
+    }
+
+    /// Position in the source image of the point displayed at `(x, y)` in the window
+    fn to_source(&self, region: Region, (x, y): (f32, f32)) -> (f32, f32) {
+        let scale = self.scale(region);
+        let left = (self.window_w - region.w * scale) / 2.0;
+        let top  = (self.window_h - region.h * scale) / 2.0;
+        (region.x + (x - left) / scale, region.y + (y - top) / scale)
+    }
 }
 
-pub fn crop_interactively(faces: &mut [Cropped], window: &show_image::WindowProxy) -> Result<(), Box<dyn std::error::Error>> {
-    let mut face_n = 0;
-    macro_rules! show { () => { window.set_image("label", faces[face_n].get()).unwrap(); }; }
+/// Rectangle of the source image, given by its top-left corner and size
+#[derive(Clone, Copy, Debug)]
+struct Region { x: f32, y: f32, w: f32, h: f32 }
+
+/// Decoded images are kept in memory for this many faces either side of the
+/// current one, and are loaded before they are needed.
+const KEEP_AROUND: usize = 4;
+
+/// Make sure that the `current` face is in memory, start loading its
+/// neighbours and forget the images of faces further away. Faces which still
+/// have to be found are searched for in the background.
+fn load_around(faces: &mut [Cropped], loader: &mut Loader, current: usize) {
+    for (n, face) in faces.iter_mut().enumerate() {
+        if n.abs_diff(current) > KEEP_AROUND { face.unload(); }
+    }
+    loop {
+        // Nearest first
+        let nearby = (0..=KEEP_AROUND)
+            .flat_map(|d| [current + d, current.wrapping_sub(d)])
+            .filter(|&n| n < faces.len() && !faces[n].is_loaded());
+        let distant = (0..faces.len())
+            .filter(|&n| n.abs_diff(current) > KEEP_AROUND && faces[n].awaiting_face);
+        loader.request(nearby .map(|n| faces[n].job(n, Keep::Proxy  ))
+               .chain(distant.map(|n| faces[n].job(n, Keep::Nothing))));
+        if faces[current].is_loaded() { return }
+        while loader.is_pending(current) {
+            let loaded = loader.recv();
+            deliver(faces, loaded, current);
+        }
+    }
+}
+
+/// Hand `loaded` over to its face, dropping the image if the user has moved
+/// too far away from it in the meantime.
+fn deliver(faces: &mut [Cropped], mut loaded: Loaded, current: usize) {
+    if loaded.n.abs_diff(current) > KEEP_AROUND { loaded.image = None; }
+    faces[loaded.n].receive(loaded);
+}
+
+/// Look for faces in all photos which have not been searched yet, without
+/// keeping the decoded images.
+pub fn detect_faces(faces: &mut [Cropped]) {
+    finish_face_detection(faces, &mut Loader::new());
+}
+
+fn finish_face_detection(faces: &mut [Cropped], loader: &mut Loader) {
+    loop {
+        loader.request((0..faces.len())
+            .filter(|&n| faces[n].awaiting_face)
+            .map(|n| faces[n].job(n, Keep::Nothing)));
+        if !loader.has_pending() { return }
+        while loader.has_pending() {
+            let mut loaded = loader.recv();
+            loaded.image = None;
+            faces[loaded.n].receive(loaded);
+        }
+    }
+}
+
+pub fn crop_interactively(
+    faces: &mut [Cropped],
+    store: &dyn MetadataStore,
+    window: &show_image::WindowProxy,
+    keymap: &Keymap,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Carry on where the previous session left off
+    let mut face_n = faces.iter().position(Cropped::needs_review).unwrap_or(0);
+    let mut history = History::default();
+    // Repeated changes with the same trigger on the same face are undone together
+    let mut last_change = None;
+    let size = window.run_function_wait(|w| w.inner_size())?;
+    let mut viewport = Viewport { window_w: size.x as f32, window_h: size.y as f32 };
+    // Window position of the mouse, while the image is being dragged
+    let mut drag_from: Option<(f32, f32)> = None;
+    let mut last_click: Option<(Instant, (f32, f32))> = None;
+    let mut view = View::default();
+    let mut guides = Guides::default();
+    let mut editing: Option<NameEntry> = None;
+    let mut help = false;
+    let mut loader = Loader::new();
+    macro_rules! show { () => {
+        load_around(faces, &mut loader, face_n);
+        let face = &faces[face_n];
+        let mut image = face.show(view, guides);
+        let status = match &editing {
+            Some(entry) => entry.status(),
+            None        => face.status(),
+        };
+        if help { display::help(&mut image, &keymap.help()); }
+        display::status_bar(&mut image, &format!("{} / {}   {status}", face_n + 1, faces.len()));
+        window.set_image("label", image).unwrap();
+    }; }
+    macro_rules! change {
+        ($trigger:expr, $change:expr) => {
+            let face = &mut faces[face_n];
+            let before = face.state();
+            $change(face);
+            if face.state() != before {
+                face.stored = true;
+                if last_change != Some((face_n, $trigger)) { history.record(face_n, before); }
+                last_change = Some((face_n, $trigger));
+            }
+            show!();
+        };
+    }
+    // Changes made after coming back to a face are undone separately
+    macro_rules! go_to { ($n:expr) => { face_n = $n; last_change = None; show!(); }; }
     show!();
     for event in window.event_channel()? {
+        while let Some(loaded) = loader.try_recv() { deliver(faces, loaded, face_n); }
         //println!("{:#?}", event);
-        if let event::WindowEvent::KeyboardInput(event) = event {
-            use event::VirtualKeyCode::*;
-            use show_image::event::KeyboardInput as KI;
-            use show_image::event::ModifiersState as MS;
-            use show_image::event::ElementState as ES;
-            let KI { scan_code: _, key_code: _, state, modifiers  } = event.input;
-            if state != ES::Pressed { continue; }
-            let mut step_size = 10;
-            if modifiers.contains(MS::CTRL ) { step_size /= 10; }
-            if modifiers.contains(MS::SHIFT) { step_size *=  5; }
-            // match event.input {
-            //     KI { key_code: Some(Escape), modifiers: MS::SHIFT.. } => {  },
-            //     _ => {},
-            // }
-            macro_rules! xxx {
-                ($method:ident) => {
-                    let face = &mut faces[face_n];
-                    face.$method(step_size);
-                    window.set_image("label", face.get()).unwrap();
+        use event::WindowEvent as WE;
+        use event::MouseButton::Left;
+        match event {
+            WE::TextInput(event) if editing.is_some() => {
+                let entry = editing.as_mut().unwrap();
+                if event.character.is_control() || entry.opening_key.is_some() { continue; }
+                entry.field().push(event.character);
+                show!();
+            },
+            WE::KeyboardInput(event) if editing.is_some() => {
+                use event::VirtualKeyCode::*;
+                let entry = editing.as_mut().unwrap();
+                if entry.opening_key.is_some() && entry.opening_key == event.input.key_code {
+                    if !event.input.state.is_pressed() { entry.opening_key = None; }
+                    continue;
+                }
+                if !event.input.state.is_pressed() { continue; }
+                match event.input.key_code {
+                    Some(Tab)    => { let entry = editing.as_mut().unwrap(); entry.editing_family = !entry.editing_family; },
+                    Some(Back)   => { editing.as_mut().unwrap().field().pop(); },
+                    Some(Escape) => { editing = None; },
+                    Some(Return) | Some(NumpadEnter) => {
+                        let NameEntry { given, family, .. } = editing.take().unwrap();
+                        let face = &mut faces[face_n];
+                        face.given  = given .trim().into();
+                        face.family = family.trim().into();
+                        face.save_metadata(store);
+                    },
+                    _ => {},
+                }
+                show!();
+            },
+            WE::Resized(event) => {
+                viewport = Viewport { window_w: event.size.x as f32, window_h: event.size.y as f32 };
+            },
+            WE::MouseButton(event) if event.button == Left => {
+                let position = (event.position.x, event.position.y);
+                if !event.state.is_pressed() { drag_from = None; continue; }
+                drag_from = Some(position);
+                last_change = None; // Each drag can be undone separately
+                let double_click = last_click.is_some_and(|(time, (x, y))| {
+                    time.elapsed() < DOUBLE_CLICK_TIME &&
+                    (x - position.0).abs() < 5.0 &&
+                    (y - position.1).abs() < 5.0
+                });
+                if double_click {
+                    let (x, y) = viewport.to_source(faces[face_n].region_shown(view), position);
+                    change!(Trigger::DoubleClick, |face: &mut Cropped| face.centre_on(x as f64, y as f64));
+                    last_click = None;
+                } else {
+                    last_click = Some((Instant::now(), position));
+                }
+            },
+            WE::MouseMove(event) => {
+                let Some((from_x, from_y)) = drag_from else { continue };
+                if !event.buttons.is_pressed(Left) { drag_from = None; continue; }
+                let scale = viewport.scale(faces[face_n].region_shown(view));
+                let dx = ((event.position.x - from_x) / scale).round();
+                let dy = ((event.position.y - from_y) / scale).round();
+                if dx == 0.0 && dy == 0.0 { continue; }
+                // In the full view the crop follows the mouse; in the crop view
+                // the image follows the mouse, so the crop goes the other way.
+                let sign = match view { View::Full => 1.0, View::Crop => -1.0 };
+                change!(Trigger::Drag, |face: &mut Cropped| face.move_by(sign * dx as f64, sign * dy as f64));
+                // Whatever could not be moved in whole pixels is carried over
+                drag_from = Some((from_x + dx * scale, from_y + dy * scale));
+            },
+            WE::MouseWheel(event) => {
+                use event::MouseScrollDelta::*;
+                let notches = match event.delta {
+                    LineDelta(_, y) => y,
+                    PixelDelta(position) => position.y as f32 / 20.0,
                 };
-            }
-            if let Some(code) = event.input.key_code {
-                match code {
-                    Escape => if event.input.state.is_pressed() { break },
+                let face = &faces[face_n];
+                let position = event.position
+                    .map(|p| viewport.to_source(face.region_shown(view), (p.x, p.y)))
+                    .unwrap_or((face.x as f32, face.y as f32));
+                // Scrolling up zooms in
+                let factor = 1.0 - notches * WHEEL_ZOOM_STEP;
+                change!(Trigger::Wheel, |face: &mut Cropped| face.zoom_around(position, factor));
+            },
+            WE::KeyboardInput(event) => {
+                use show_image::event::KeyboardInput as KI;
+                use show_image::event::ElementState as ES;
+                let KI { scan_code: _, key_code: _, state, modifiers  } = event.input;
+                if state != ES::Pressed { continue; }
+                let multiplier = keymap.multiplier(modifiers);
+                let step_size = keymap.steps.pixels * multiplier;
+                let Some(code) = event.input.key_code else { continue };
+                macro_rules! xxx { ($method:ident) => { change!(Trigger::Key(code), |face: &mut Cropped| face.$method(step_size)); }; }
+                macro_rules! adjust {
+                    ($setting:ident, $direction:expr) => {
+                        // Adjustments come in whole steps, so there is no finer
+                        // version, and a fractional coarse one is rounded
+                        let steps = $direction * (multiplier as f32).round().max(1.0);
+                        change!(Trigger::Key(code), |face: &mut Cropped| face.adjust(Setting::$setting, steps));
+                    };
+                }
+                macro_rules! undo_redo {
+                    ($method:ident) => {
+                        if let Some((n, state)) = history.$method(|n| faces[n].state()) {
+                            face_n = n;
+                            load_around(faces, &mut loader, n);
+                            faces[n].restore(state);
+                            faces[n].stored = true;
+                            show!();
+                        }
+                        last_change = None;
+                    };
+                }
+                let Some(action) = keymap.action(code) else { continue };
+                use Action::*;
+                match action {
+                    Quit  =>  { break },
+                    Help  =>  { help = !help; show!(); },
                     Up    =>  { xxx!(up      ); },
                     Down  =>  { xxx!(down    ); },
                     Left  =>  { xxx!(left    ); },
                     Right =>  { xxx!(right   ); },
-                    P     =>  { xxx!(zoom_out); },
-                    G     =>  { xxx!(zoom_in ); },
-                    Back  =>  { face_n = face_n.saturating_sub(1);             show!(); },
-                    Space =>  { face_n = (face_n + 1).clamp(0, faces.len()-1); show!(); },
-                    _ => {},
+                    ZoomOut => { xxx!(zoom_out); },
+                    ZoomIn  => { xxx!(zoom_in ); },
+                    Rotate  => { change!(Trigger::Key(code), Cropped::rotate_clockwise); },
+                    Undo  =>  { undo_redo!(undo); },
+                    Redo  =>  { undo_redo!(redo); },
+                    ToggleView  => { view   = view  .toggle(); show!(); },
+                    CycleGuides => { guides = guides.next  (); show!(); },
+                    Accept => {
+                        // Saved right away, so that the work survives a crash
+                        faces[face_n].reviewed = true;
+                        faces[face_n].save_metadata(store);
+                        go_to!((face_n + 1).clamp(0, faces.len()-1));
+                    },
+                    ToggleExcluded => {
+                        faces[face_n].excluded = !faces[face_n].excluded;
+                        faces[face_n].save_metadata(store);
+                        show!();
+                    },
+                    BrightnessDown => { adjust!(Brightness , -1.0); },
+                    BrightnessUp   => { adjust!(Brightness ,  1.0); },
+                    ContrastDown   => { adjust!(Contrast   , -1.0); },
+                    ContrastUp     => { adjust!(Contrast   ,  1.0); },
+                    GammaDown      => { adjust!(Gamma      , -1.0); },
+                    GammaUp        => { adjust!(Gamma      ,  1.0); },
+                    Cooler         => { adjust!(Temperature, -1.0); },
+                    Warmer         => { adjust!(Temperature,  1.0); },
+                    ResetAdjustments => { change!(Trigger::Key(code), |face: &mut Cropped| face.adjustments = None); },
+                    EditNames => { editing = Some(NameEntry::new(&faces[face_n], code)); show!(); },
+                    NextUnreviewed => {
+                        match next_unreviewed(faces, face_n) {
+                            Some(n) => { go_to!(n); },
+                            None    => println!("All faces have been reviewed"),
+                        }
+                    },
+                    Previous => { go_to!(face_n.saturating_sub(1));             },
+                    Next     => { go_to!((face_n + 1).clamp(0, faces.len()-1)); },
                 }
-            }
+            },
+            _ => {},
         }
     }
 
-    let start_all = Instant::now();
-    for face in faces {
-        let start = Instant::now();
-        face.save_metadata();
-        println!("Embedded metadata in {} in {:.0?}",
-                 face.path.display(),
-                 start.elapsed(),
-        );
+    finish_face_detection(faces, &mut loader);
+
+    let start = Instant::now();
+    let saved = save_changed_metadata(faces.iter_mut(), store);
+    println!("Saving metadata of {saved} changed faces took {:.0?}", start.elapsed());
+
+    let included = faces.iter().filter(|f| !f.excluded).count();
+    let unreviewed = faces.iter().filter(|f| f.needs_review()).count();
+    if unreviewed > 0 {
+        println!("{unreviewed} of {included} faces still need to be reviewed");
+    } else {
+        println!("All {included} faces have been reviewed");
     }
-    println!("Saving metadata took {:.0?}", start_all.elapsed());
 
     Ok(())
 }
 
-pub fn write_cropped_images(faces: &[Cropped], dir: impl AsRef<Path>) {
-    std::fs::create_dir_all(&dir).unwrap();
-    for face in faces {
-        //let filename = format!("{} @ {}.jpg", dbg!(&face.given), dbg!(&face.family));
-        let filename = face.path.file_name().unwrap().to_string_lossy();
-        let path = dir.as_ref().join(&*filename);
-        let file = &mut File::create(path).unwrap();
-        let mut encoder = JpegEncoder::new(file);
-        let image_bytes = face.get().as_bytes().to_owned();
-        encoder.encode(&image_bytes, face.w as u32, face.h() as u32, image::ExtendedColorType::Rgb8).unwrap();
+/// Write the metadata of those `faces` whose metadata the store does not hold
+/// already, all in one go, leaving out guessed crops: saving them would pass
+/// them off as chosen by someone, and rewrite photos nobody looked at. Returns
+/// how many were written.
+fn save_changed_metadata<'a>(faces: impl IntoIterator<Item = &'a mut Cropped>, store: &dyn MetadataStore) -> usize {
+    let mut changed = faces.into_iter()
+        .filter(|face| face.stored)
+        .filter_map(|face| Some((face.unsaved_metadata()?, face)))
+        .collect::<Vec<_>>();
+    store.save_all(&changed.iter().map(|(metadata, face)| (face.path.as_path(), metadata)).collect::<Vec<_>>());
+    for (metadata, face) in &mut changed { face.saved = Some(metadata.clone()); }
+    changed.len()
+}
+
+/// Find corrections which make the exposure and skin tones of all faces
+/// consistent, and store them as the faces' default adjustments.
+pub fn normalise_exposure(faces: &mut [Cropped], store: &dyn MetadataStore) {
+    let jobs = faces.iter().enumerate()
+        .filter(|(_, f)| !f.excluded)
+        .map(|(n, f)| f.job(n, Keep::Proxy))
+        .collect::<Vec<_>>();
+    let mut colours = vec![];
+    Loader::new().run_all(jobs, |mut loaded| {
+        let n = loaded.n;
+        let proxy = loaded.image.take().unwrap();
+        // Also takes delivery of the face, if it had not been found yet
+        faces[n].receive(loaded);
+        colours.push((n, adjust::face_colour(&faces[n].crop_of(&proxy).to_rgb8())));
+    });
+    let adjustments = adjust::normalise(&colours.iter().map(|&(_, c)| c).collect::<Vec<_>>());
+    for ((n, _), adjustments) in colours.into_iter().zip(adjustments) {
+        faces[n].default_adjustments = Some(adjustments);
+    }
+    save_changed_metadata(faces.iter_mut(), store);
+    println!("Normalised the exposure of {} faces", faces.iter().filter(|f| !f.excluded).count());
+}
+
+/// File format of the cropped images, whatever the format of the photos
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    #[default]
+    Jpeg,
+    Png,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png  => "png",
+        }
+    }
+}
+
+/// Shape of the crops, shared by the whole class
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Deserialize)]
+pub enum AspectRatio {
+    /// Portrait, 2 wide by 3 high
+    #[default]
+    #[serde(rename = "2:3")]
+    TwoByThree,
+    /// Portrait, 4 wide by 5 high
+    #[serde(rename = "4:5")]
+    FourByFive,
+    #[serde(rename = "square", alias = "1:1")]
+    Square,
+}
+
+impl AspectRatio {
+    /// Height to width, as used by `Cropped`
+    pub fn height_to_width(self) -> (i32, i32) {
+        match self {
+            AspectRatio::TwoByThree => (3, 2),
+            AspectRatio::FourByFive => (5, 4),
+            AspectRatio::Square     => (1, 1),
+        }
+    }
+}
+
+/// How the cropped images are written
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Output {
+    pub format: OutputFormat,
+    /// Width of the cropped images, in pixels. They keep their natural size if
+    /// neither this nor `dpi` is given.
+    pub width: Option<u32>,
+    /// Resolution at which the crops are printed in the trombinoscope, which
+    /// determines their width unless `width` is given
+    pub dpi: Option<u32>,
+    /// JPEG quality, from 1 to 100
+    pub quality: u8,
+    /// Apply mild sharpening after resizing
+    pub sharpen: bool,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Self { format: OutputFormat::default(), width: None, dpi: None, quality: 90, sharpen: false }
+    }
+}
+
+impl Output {
+    /// Reject settings which the crops cannot be written with
+    pub fn check(&self) -> Result<(), String> {
+        if !(1..=100).contains(&self.quality) {
+            return Err(format!("`quality` in [output] must be from 1 to 100, not {}", self.quality));
+        }
+        for (name, value) in [("width", self.width), ("dpi", self.dpi)] {
+            if value == Some(0) { return Err(format!("`{name}` in [output] must be greater than 0")); }
+        }
+        Ok(())
+    }
+}
+
+impl Cropped {
+    /// Name of the file into which the crop is written
+    pub fn output_name(&self, format: OutputFormat) -> PathBuf {
+        let name = self.path.file_name().unwrap();
+        if !self.output_keeps_extension { return Path::new(name).with_extension(format.extension()) }
+        let mut name = name.to_owned();
+        name.push(".");
+        name.push(format.extension());
+        name.into()
+    }
+
+    /// Identifies the image in the photo's file, ignoring our metadata, if the
+    /// file can be read
+    fn source_hash(&self) -> Option<u64> {
+        match std::fs::read(&self.path) {
+            Ok(bytes) => Some(hash_of(metadata::image_content(&bytes))),
+            Err(err) => {
+                println!("WARNING: could not read {}, its crop is left as it was: {err}", self.path.display());
+                None
+            },
+        }
+    }
+
+    /// Changes whenever the crop written by `write_crop` would, given the
+    /// `source_hash`
+    fn crop_hash(&self, source_hash: u64, output: &Output) -> u64 {
+        let &Self { x, y, w, r, .. } = self;
+        let corrections = bitcode::encode(&(self.current_orientation(), self.adjustments()));
+        hash_of((source_hash, [x, y, w].map(f64::to_bits), r, corrections, output))
+    }
+}
+
+/// Give distinct crop names to photos whose names differ only in their
+/// extensions, such as `Dupont Marie.jpg` and `Dupont Marie.png`, which would
+/// otherwise overwrite each other's crops.
+pub fn disambiguate_output_names(faces: &mut [Cropped]) {
+    let stem = |face: &Cropped| face.path.file_stem().map(|stem| stem.to_string_lossy().to_lowercase());
+    let mut count = BTreeMap::new();
+    for face in faces.iter() { *count.entry(stem(face)).or_insert(0) += 1; }
+    for face in faces.iter_mut().filter(|face| count[&stem(face)] > 1) {
+        face.output_keeps_extension = true;
+        println!("WARNING: {} only differs from another photo in its extension, which is kept in the name of its crop",
+                 face.path.display());
+    }
+}
+
+/// Stable across runs, unlike `DefaultHasher`, which is seeded randomly. The
+/// `Hash` implementations of the standard library may change with the version
+/// of Rust, which would only cause all crops to be written again, once.
+fn hash_of(value: impl Hash) -> u64 {
+    let mut hasher = SipHasher13::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Name of the file, among the crops, which records the `crop_hash` of each
+/// crop, so that only those which have changed are written again
+const CROP_HASHES: &str = "trombinoscope.crops";
+
+/// Write the crops of all faces which are not excluded into `dir`, taken from
+/// the full size photos, which are decoded in parallel. Crops which are already
+/// up to date are left alone, and those which are no longer needed are removed.
+/// Those of photos which cannot be read are left as they were, and tried again
+/// next time.
+///
+/// Face detection must have been completed (see `detect_faces`).
+/// `output.width` is used as is: `dpi` must have been converted into a width
+/// by the caller, who knows the size of the pictures on the page.
+pub fn write_cropped_images(faces: &[Cropped], dir: impl AsRef<Path>, output: &Output) {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir).unwrap();
+    let hashes_file = dir.join(CROP_HASHES);
+    let old_hashes: BTreeMap<String, u64> = std::fs::read(&hashes_file).ok()
+        .and_then(|bytes| bitcode::decode(&bytes).ok())
+        .unwrap_or_default();
+    let mut hashes = BTreeMap::new();
+    let mut wanted = BTreeSet::new();
+    let mut jobs = vec![];
+    let name = |face: &Cropped| face.output_name(output.format).to_string_lossy().into_owned();
+    let included = faces.iter().enumerate().filter(|(_, f)| !f.excluded).collect::<Vec<_>>();
+    let source_hashes = source_hashes(&included.iter().map(|&(_, f)| f).collect::<Vec<_>>());
+    for ((n, face), source_hash) in included.into_iter().zip(source_hashes) {
+        let name = name(face);
+        wanted.insert(name.clone());
+        let Some(source_hash) = source_hash else { continue };
+        let hash = face.crop_hash(source_hash, output);
+        if old_hashes.get(&name) != Some(&hash) || !dir.join(&name).exists() {
+            jobs.push(face.job(n, Keep::Full));
+        }
+        hashes.insert(name, hash);
+    }
+    let unchanged = hashes.len() - jobs.len();
+    let mut written = 0;
+    Loader::new().run_all(jobs, |Loaded { n, image, decoded, .. }| {
+        if !decoded {
+            // Its blank image would pass for the crop, up to date until the photo changes
+            println!("WARNING: the crop of {} is left as it was", faces[n].path.display());
+            hashes.remove(&name(&faces[n]));
+            return
+        }
+        write_crop(&faces[n], &image.unwrap(), dir, output);
+        written += 1;
+    });
+    println!("Wrote {written} crops, {unchanged} were up to date");
+    remove_stale_crops(dir, &wanted);
+    std::fs::write(&hashes_file, bitcode::encode(&hashes)).unwrap();
+}
+
+/// The `source_hash` of each of `faces`, whose photos are read in parallel, as
+/// they may be on a slow drive.
+fn source_hashes(faces: &[&Cropped]) -> Vec<Option<u64>> {
+    let threads = std::thread::available_parallelism().map_or(4, usize::from);
+    let per_thread = faces.len().div_ceil(threads).max(1);
+    std::thread::scope(|scope| {
+        let hashing = faces.chunks(per_thread)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(|face| face.source_hash()).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        hashing.into_iter().flat_map(|thread| thread.join().unwrap()).collect()
+    })
+}
+
+/// Remove the crops in `dir` which are not among `wanted`: those of photos
+/// which have been deleted or excluded, or written in another format.
+fn remove_stale_crops(dir: &Path, wanted: &BTreeSet<String>) {
+    let extensions = [OutputFormat::Jpeg, OutputFormat::Png].map(OutputFormat::extension);
+    for path in std::fs::read_dir(dir).unwrap().filter_map(|e| e.ok()).map(|e| e.path()) {
+        let is_crop = path.extension().and_then(OsStr::to_str).is_some_and(|e| extensions.contains(&e));
+        let name = path.file_name().unwrap().to_string_lossy();
+        if is_crop && !wanted.contains(name.as_ref()) {
+            std::fs::remove_file(&path).unwrap();
+            println!("Removed stale crop {}", path.display());
+        }
+    }
+}
+
+/// Standard deviation and threshold of the unsharp mask used by `Output::sharpen`
+const SHARPEN: (f32, i32) = (0.8, 2);
+
+fn write_crop(face: &Cropped, image: &DynamicImage, dir: impl AsRef<Path>, output: &Output) {
+    let path = dir.as_ref().join(face.output_name(output.format));
+    let mut cropped = face.crop_of(image).to_rgb8();
+    face.adjustments().apply(&mut cropped);
+    if let Some(width) = output.width {
+        let height = face.h_for(width as f64).round() as u32;
+        cropped = imageops::resize(&cropped, width, height, imageops::FilterType::Lanczos3);
+    }
+    if output.sharpen {
+        let (sigma, threshold) = SHARPEN;
+        cropped = imageops::unsharpen(&cropped, sigma, threshold);
+    }
+    match output.format {
+        OutputFormat::Jpeg => {
+            let file = &mut File::create(path).unwrap();
+            let mut encoder = JpegEncoder::new_with_quality(file, output.quality);
+            let (w, h) = cropped.dimensions();
+            encoder.encode(cropped.as_raw(), w, h, image::ExtendedColorType::Rgb8).unwrap();
+        },
+        OutputFormat::Png => cropped.save_with_format(path, ImageFormat::Png).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use rstest::rstest;
+    use pretty_assertions::assert_eq;
+
+    /// A 2:3 crop centred on `(x, y)`, `w` wide, in a 1000 by 800 photo
+    fn cropped((x, y, w): (f64, f64, f64)) -> Cropped {
+        let mut cropped = Cropped::new("Zoé @ Martin.jpg", (1000, 800), Orientation::default(), (3, 2));
+        (cropped.x, cropped.y, cropped.w) = (x, y, w);
+        cropped
+    }
+
+    /// Rounded to a thousandth of a pixel
+    fn crop(cropped: &Cropped) -> (f64, f64, f64) {
+        let round = |n: f64| (n * 1000.0).round() / 1000.0;
+        (round(cropped.x), round(cropped.y), round(cropped.w))
+    }
+
+    #[rstest]
+    #[case::inside     ((500.0, 400.0, 200.0), ( 10.0,   0.0), (510.0, 400.0, 200.0))]
+    #[case::left       ((120.0, 400.0, 200.0), (-50.0,   0.0), (100.0, 400.0, 200.0))]
+    #[case::right      ((880.0, 400.0, 200.0), ( 50.0,   0.0), (900.0, 400.0, 200.0))]
+    #[case::top        ((500.0, 160.0, 200.0), (  0.0, -50.0), (500.0, 150.0, 200.0))]
+    #[case::bottom     ((500.0, 640.0, 200.0), (  0.0,  50.0), (500.0, 650.0, 200.0))]
+    #[case::corner     ((120.0, 160.0, 200.0), (-50.0, -50.0), (100.0, 150.0, 200.0))]
+    #[case::far_beyond ((500.0, 400.0, 200.0), (5e3  ,   0.0), (900.0, 400.0, 200.0))]
+    #[case::sub_pixel  ((500.0, 400.0, 200.0), (  0.25,  0.5), (500.25, 400.5, 200.0))]
+    fn moves_stop_at_the_edges(
+        #[case] start: (f64, f64, f64),
+        #[case] (dx, dy): (f64, f64),
+        #[case] expected: (f64, f64, f64),
+    ) {
+        let mut cropped = cropped(start);
+        cropped.move_by(dx, dy);
+        assert_eq!(crop(&cropped), expected);
+    }
+
+    #[rstest]
+    #[case::inside         ((500.0, 400.0, 200.0),   50.0, (500.0  , 400.0, 250.0  ))]
+    #[case::left_edge      ((110.0, 400.0, 200.0),   50.0, (125.0  , 400.0, 250.0  ))]
+    #[case::bottom_edge    ((500.0, 640.0, 200.0),   50.0, (500.0  , 612.5, 250.0  ))]
+    #[case::beyond_photo   ((900.0, 400.0, 200.0),  800.0, (733.333, 400.0, 533.333))]
+    #[case::down_to_nothing((500.0, 400.0, 200.0), -300.0, (500.0  , 400.0,  20.0  ))]
+    fn zooming_out_shifts_the_centre_to_stay_inside(
+        #[case] start: (f64, f64, f64),
+        #[case] n: f64,
+        #[case] expected: (f64, f64, f64),
+    ) {
+        let mut cropped = cropped(start);
+        cropped.zoom_out(n);
+        assert_eq!(crop(&cropped), expected);
+    }
+
+    // The face's centre sits 0.42 of the way down a 2:3 crop, 2.2 times as wide as the face
+    #[rstest]
+    #[case::centred     ((500, 300, 100), (500.0, 326.4, 220.0  ))]
+    #[case::left_edge   (( 40, 300, 100), (110.0, 326.4, 220.0  ))]
+    #[case::right_edge  ((990, 300, 100), (890.0, 326.4, 220.0  ))]
+    #[case::top_edge    ((500,  60, 100), (500.0, 165.0, 220.0  ))]
+    #[case::corner      ((  0, 790, 100), (110.0, 635.0, 220.0  ))]
+    #[case::larger_than ((500, 300, 400), (500.0, 400.0, 533.333))]
+    fn faces_are_framed_inside_the_photo(
+        #[case] (x, y, w): (i32, i32, i32),
+        #[case] expected: (f64, f64, f64),
+    ) {
+        let mut cropped = cropped((500.0, 400.0, 200.0));
+        cropped.frame_face(Face { x, y, w, h: w * 6 / 5 });
+        assert_eq!(crop(&cropped), expected);
+        let Region { x, y, w, h } = cropped.region();
+        assert!((h / w - 1.5).abs() < 1e-9, "{:?}", cropped.region());
+        assert!(x >= 0.0 && y >= 0.0 && x + w <= 1000.0 && y + h <= 800.0, "{:?}", cropped.region());
+    }
+
+    #[test]
+    fn wheel_zoom_at_the_edge_keeps_the_crop_inside() {
+        let mut cropped = cropped((110.0, 160.0, 200.0));
+        cropped.zoom_around((10.0, 10.0), 1.5);
+        let Region { x, y, w, h } = cropped.region();
+        assert_eq!(crop(&cropped).2, 300.0);
+        assert!(x >= 0.0 && y >= 0.0 && x + w <= 1000.0 && y + h <= 800.0, "{:?}", cropped.region());
+    }
+
+    #[test]
+    fn crop_follows_the_photo_when_it_is_resized() {
+        let metadata = cropped((333.3, 271.7, 123.4)).metadata();
+        let mut resized = Cropped::new("Zoé @ Martin.jpg", (500, 400), Orientation::default(), (3, 2));
+        resized.set_metadata(metadata.clone());
+        assert_eq!(crop(&resized), (166.65, 135.85, 61.7));
+        assert_eq!(resized.metadata(), metadata);
+    }
+
+    #[test]
+    fn changing_the_aspect_ratio_keeps_the_centre_and_width_for_review() {
+        let mut cropped = cropped((500.0, 300.0, 400.0));
+        cropped.reviewed = true;
+        let metadata = cropped.metadata();
+        let mut square = Cropped::new("Zoé @ Martin.jpg", (1000, 800), Orientation::default(), (1, 1));
+        square.set_metadata(metadata);
+        assert_eq!(crop(&square), (500.0, 300.0, 400.0));
+        assert_eq!((square.h(), square.metadata().r), (400.0, (1, 1)));
+        assert!(square.needs_review());
+        // The taller crop did not fit so high up
+        let mut tall = Cropped::new("Zoé @ Martin.jpg", (1000, 800), Orientation::default(), (2, 1));
+        tall.set_metadata(square.metadata());
+        assert_eq!(crop(&tall), (500.0, 400.0, 400.0));
+    }
+
+    #[test]
+    fn photos_differing_only_in_extension_get_distinct_crops() {
+        let face = |name| Cropped::new(name, (1000, 800), Orientation::default(), (3, 2));
+        let mut faces = [face("Dupont Marie.jpg"), face("Dupont Marie.PNG"), face("Martin Zoé.jpeg")];
+        disambiguate_output_names(&mut faces);
+        let names = faces.map(|face| face.output_name(OutputFormat::Jpeg));
+        assert_eq!(names, ["Dupont Marie.jpg.jpg", "Dupont Marie.PNG.jpg", "Martin Zoé.jpg"].map(PathBuf::from));
+    }
+
+    /// Faces of `n` small photos, in a directory of their own
+    fn faces(n: usize) -> (TestDir, Vec<Cropped>) {
+        let dir = TestDir::new();
+        let faces = dir.photos(n).into_iter()
+            .map(|path| Cropped::new(path, (40, 30), Orientation::default(), (3, 2)))
+            .collect();
+        (dir, faces)
+    }
+
+    #[test]
+    fn only_faces_near_the_current_one_are_kept_in_memory() {
+        let (_dir, mut faces) = faces(2 * KEEP_AROUND + 4);
+        let mut loader = Loader::new();
+        for current in [0, faces.len() - 1, 5] {
+            load_around(&mut faces, &mut loader, current);
+            while loader.has_pending() { deliver(&mut faces, loader.recv(), current); }
+            let loaded = (0..faces.len()).filter(|&n| faces[n].is_loaded()).collect::<Vec<_>>();
+            let expected = (current.saturating_sub(KEEP_AROUND)..=current + KEEP_AROUND).filter(|&n| n < faces.len()).collect::<Vec<_>>();
+            assert_eq!(loaded, expected);
+        }
+    }
+
+    #[test]
+    fn results_for_another_orientation_are_ignored() {
+        let (_dir, mut faces) = faces(1);
+        let mut loader = Loader::new();
+        loader.request([faces[0].job(0, Keep::Proxy)]);
+        let loaded = loader.recv();
+        faces[0].orientation = Some(Orientation::default().rotated_clockwise());
+        faces[0].receive(loaded);
+        assert!(!faces[0].is_loaded());
+        load_around(&mut faces, &mut loader, 0);
+        assert_eq!(faces[0].proxy().width(), 30);
+    }
+
+    #[test]
+    fn normalising_does_not_store_guessed_crops() {
+        let (_dir, faces) = faces(3);
+        let store = metadata::SidecarPerImage;
+        store.save(&faces[0].path, &cropped((20.0, 15.0, 10.0)).metadata());
+        let load = || faces.iter()
+            .map(|f| Cropped::load(&f.path, &store, AspectRatio::default()).unwrap())
+            .collect::<Vec<_>>();
+        let mut loaded = load();
+        normalise_exposure(&mut loaded, &store);
+        assert!(loaded.iter().all(|face| face.default_adjustments.is_some()));
+        let stored = load().iter().map(|face| (face.has_been_cropped(), face.default_adjustments.is_some())).collect::<Vec<_>>();
+        assert_eq!(stored, [(true, true), (false, false), (false, false)]);
+    }
+
+    #[test]
+    fn only_crops_chosen_by_someone_are_saved() {
+        let (_dir, mut faces) = faces(3);
+        let store = metadata::SidecarPerImage;
+        // Found by face detection, and accepted
+        faces[0].frame_face(Face { x: 10, y: 5, w: 10, h: 12 });
+        faces[1].frame_face(Face { x: 10, y: 5, w: 10, h: 12 });
+        faces[0].reviewed = true;
+        faces[0].save_metadata(&store);
+        assert_eq!(save_changed_metadata(faces.iter_mut(), &store), 0);
+        let cropped = faces.iter()
+            .map(|f| Cropped::load(&f.path, &store, AspectRatio::default()).unwrap().has_been_cropped())
+            .collect::<Vec<_>>();
+        assert_eq!(cropped, [true, false, false]);
+    }
+
+    /// The crops written into `dir`, each of which is replaced by a mark, to
+    /// show whether it is written again
+    fn written_and_marked(dir: &Path) -> Vec<(String, bool)> {
+        let mut crops = std::fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap() != CROP_HASHES)
+            .map(|path| {
+                let written = std::fs::read(&path).unwrap() != b"mark";
+                std::fs::write(&path, b"mark").unwrap();
+                (path.file_name().unwrap().to_string_lossy().into_owned(), written)
+            })
+            .collect::<Vec<_>>();
+        crops.sort();
+        crops
+    }
+
+    #[test]
+    fn only_changed_crops_are_written_again() {
+        let (photos, mut faces) = faces(3);
+        let dir = photos.join("crops");
+        let output = Output::default();
+        let crops = |written: [bool; 3]| (0..3).map(|n| format!("{n} @ Photo.jpg")).zip(written).collect::<Vec<_>>();
+        write_cropped_images(&faces, &dir, &output);
+        assert_eq!(written_and_marked(&dir), crops([true, true, true]));
+        write_cropped_images(&faces, &dir, &output);
+        assert_eq!(written_and_marked(&dir), crops([false, false, false]));
+        // Changed crop of the first, changed source of the second
+        faces[0].zoom_out(2.0);
+        image::RgbImage::from_pixel(40, 30, [9, 9, 9].into()).save(&faces[1].path).unwrap();
+        write_cropped_images(&faces, &dir, &output);
+        assert_eq!(written_and_marked(&dir), crops([true, true, false]));
+    }
+
+    #[test]
+    fn crops_of_unreadable_photos_are_kept_and_written_later() {
+        let (photos, faces) = faces(2);
+        let dir = photos.join("crops");
+        let crops = |written: [bool; 2]| (0..2).map(|n| format!("{n} @ Photo.jpg")).zip(written).collect::<Vec<_>>();
+        write_cropped_images(&faces, &dir, &Output::default());
+        written_and_marked(&dir);
+        let originals = faces.iter().map(|face| std::fs::read(&face.path).unwrap()).collect::<Vec<_>>();
+        std::fs::write(&faces[0].path, b"not a photo any more").unwrap();
+        std::fs::remove_file(&faces[1].path).unwrap();
+        write_cropped_images(&faces, &dir, &Output::default());
+        assert_eq!(written_and_marked(&dir), crops([false, false]));
+        // Readable again, as they were
+        for (face, original) in faces.iter().zip(originals) { std::fs::write(&face.path, original).unwrap(); }
+        write_cropped_images(&faces, &dir, &Output::default());
+        assert_eq!(written_and_marked(&dir), crops([true, true]));
+    }
+
+    #[rstest]
+    #[case::natural(None    , (10, 15))]
+    #[case::larger (Some(20), (20, 30))]
+    #[case::smaller(Some(4 ), ( 4,  6))]
+    fn crops_are_written_at_the_configured_width(#[case] width: Option<u32>, #[case] expected: (u32, u32)) {
+        let (photos, mut faces) = faces(1);
+        faces[0].xxx(20.0, 15.0, 10.0);
+        let dir = photos.join("crops");
+        write_cropped_images(&faces, &dir, &Output { width, sharpen: true, ..Output::default() });
+        assert_eq!(image::image_dimensions(dir.join(faces[0].output_name(OutputFormat::Jpeg))).unwrap(), expected);
+    }
+
+    #[test]
+    fn stale_crops_are_removed() {
+        let (photos, mut faces) = faces(3);
+        let dir = photos.join("crops");
+        write_cropped_images(&faces, &dir, &Output::default());
+        written_and_marked(&dir);
+        // The photo of the last face has been deleted, the first is excluded
+        faces.pop();
+        faces[0].excluded = true;
+        write_cropped_images(&faces, &dir, &Output::default());
+        assert_eq!(written_and_marked(&dir), [("1 @ Photo.jpg".into(), false)]);
+        let png = Output { format: OutputFormat::Png, ..Output::default() };
+        write_cropped_images(&faces, &dir, &png);
+        assert_eq!(written_and_marked(&dir), [("1 @ Photo.png".into(), true)]);
+    }
+
+    #[test]
+    fn pixel_crops_are_migrated_to_fractions() {
+        let mut cropped = cropped((0.0, 0.0, 0.0));
+        let crop_box = CropBox::Pixels { x: 500, y: 400, w: 250 };
+        cropped.set_metadata(Metadata { crop: crop_box, ..cropped.metadata() });
+        assert_eq!(crop(&cropped), (500.0, 400.0, 250.0));
+        assert_eq!(cropped.metadata().crop, CropBox::Fraction { x: 0.5, y: 0.5, w: 0.25 });
     }
 }
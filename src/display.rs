@@ -0,0 +1,175 @@
+use std::sync::OnceLock;
+
+use ab_glyph::{Font, FontRef, PxScale, PxScaleFont, ScaleFont, point};
+use image::{Rgb, RgbImage};
+
+/// Rectangle in pixels, given by its top-left corner and size
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rect { pub x: i32, pub y: i32, pub w: i32, pub h: i32 }
+
+const OUTLINE: Rgb<u8> = Rgb([255, 220,   0]);
+const GUIDE  : Rgb<u8> = Rgb([  0, 200, 255]);
+const TEXT   : Rgb<u8> = Rgb([255, 255, 255]);
+const BAR    : Rgb<u8> = Rgb([  0,   0,   0]);
+/// Brightness of the image outside of the crop, in the full view
+const DIM: f32 = 0.35;
+
+/// Height, as a fraction of the crop height from its top, at which the eyes
+/// should be. Matches the framing of crops seeded by face detection.
+pub const EYE_LINE: f32 = 0.37;
+
+/// Lines drawn on the crop, to help frame all faces consistently
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Guides {
+    #[default]
+    None,
+    /// Eye line and vertical centre line
+    Framing,
+    /// Rule of thirds
+    Thirds,
+}
+
+impl Guides {
+    pub fn next(self) -> Self {
+        match self {
+            Guides::None    => Guides::Framing,
+            Guides::Framing => Guides::Thirds,
+            Guides::Thirds  => Guides::None,
+        }
+    }
+}
+
+fn blend(pixel: &mut Rgb<u8>, colour: Rgb<u8>, alpha: f32) {
+    for (p, c) in pixel.0.iter_mut().zip(colour.0) {
+        *p = (*p as f32 * (1.0 - alpha) + c as f32 * alpha) as u8;
+    }
+}
+
+fn fill(image: &mut RgbImage, Rect { x, y, w, h }: Rect, colour: Rgb<u8>, alpha: f32) {
+    let x0 = x.max(0) as u32; let x1 = ((x + w).max(0) as u32).min(image.width ());
+    let y0 = y.max(0) as u32; let y1 = ((y + h).max(0) as u32).min(image.height());
+    for yy in y0..y1 {
+        for xx in x0..x1 {
+            blend(image.get_pixel_mut(xx, yy), colour, alpha);
+        }
+    }
+}
+
+/// Darken everything outside of `rect`.
+pub fn dim_outside(image: &mut RgbImage, rect: Rect) {
+    let inside = |x: u32, y: u32| {
+        let (x, y) = (x as i32, y as i32);
+        x >= rect.x && x < rect.x + rect.w && y >= rect.y && y < rect.y + rect.h
+    };
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        if !inside(x, y) {
+            pixel.0 = pixel.0.map(|c| (c as f32 * DIM) as u8);
+        }
+    }
+}
+
+/// Draw the border of `rect`, `thickness` pixels wide, just outside of it.
+pub fn outline(image: &mut RgbImage, Rect { x, y, w, h }: Rect, thickness: i32) {
+    let t = thickness;
+    fill(image, Rect { x: x - t, y: y - t, w: w + 2*t, h: t }, OUTLINE, 1.0);
+    fill(image, Rect { x: x - t, y: y + h, w: w + 2*t, h: t }, OUTLINE, 1.0);
+    fill(image, Rect { x: x - t, y       , w: t      , h    }, OUTLINE, 1.0);
+    fill(image, Rect { x: x + w, y       , w: t      , h    }, OUTLINE, 1.0);
+}
+
+/// Draw `guides` over the crop occupying `rect`.
+pub fn guides(image: &mut RgbImage, guides: Guides, Rect { x, y, w, h }: Rect, thickness: i32) {
+    let t = thickness;
+    let horizontal = |image: &mut RgbImage, fraction: f32| {
+        let yy = y + (h as f32 * fraction) as i32 - t / 2;
+        fill(image, Rect { x, y: yy, w, h: t }, GUIDE, 0.6);
+    };
+    let vertical = |image: &mut RgbImage, fraction: f32| {
+        let xx = x + (w as f32 * fraction) as i32 - t / 2;
+        fill(image, Rect { x: xx, y, w: t, h }, GUIDE, 0.6);
+    };
+    match guides {
+        Guides::None => {},
+        Guides::Framing => {
+            horizontal(image, EYE_LINE);
+            vertical  (image, 0.5);
+        },
+        Guides::Thirds => {
+            for fraction in [1.0 / 3.0, 2.0 / 3.0] {
+                horizontal(image, fraction);
+                vertical  (image, fraction);
+            }
+        },
+    }
+}
+
+/// Write `text` in a band across the bottom of the image, scaled to fit.
+pub fn status_bar(image: &mut RgbImage, text: &str) {
+    let font = font();
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    // Width of the text at 1px per em, so it can be scaled to fit
+    let unit_width = text_width(font, PxScale::from(1.0), text);
+    let size = (height / 25.0).min(0.95 * width / unit_width).max(1.0);
+    let font = font.as_scaled(PxScale::from(size));
+    let bar_h = (font.height() * 1.3).ceil() as i32;
+    let bar = Rect { x: 0, y: image.height() as i32 - bar_h, w: image.width() as i32, h: bar_h };
+    fill(image, bar, BAR, 0.6);
+
+    let left = (width - text_width(font.font(), font.scale(), text)) / 2.0;
+    let baseline = bar.y as f32 + (bar_h as f32 - font.height()) / 2.0 + font.ascent();
+    draw_text(image, &font, text, left, baseline);
+}
+
+/// Write `lines` over the darkened image, scaled to fit, in the middle of
+/// the space above the status bar.
+pub fn help(image: &mut RgbImage, lines: &[String]) {
+    let font = font();
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let unit_width = lines.iter().map(|line| text_width(font, PxScale::from(1.0), line)).fold(0.0, f32::max);
+    let unit_height = font.as_scaled(PxScale::from(1.0)).height() * 1.2;
+    let size = (height / 25.0)
+        .min(0.9 * width  / unit_width)
+        .min(0.8 * height / (unit_height * lines.len() as f32))
+        .max(1.0);
+    let font = font.as_scaled(PxScale::from(size));
+    fill(image, Rect { x: 0, y: 0, w: image.width() as i32, h: image.height() as i32 }, BAR, 0.75);
+
+    let line_h = font.height() * 1.2;
+    let left = width * 0.05;
+    let top = (height * 0.9 - line_h * lines.len() as f32) / 2.0;
+    for (n, line) in lines.iter().enumerate() {
+        draw_text(image, &font, line, left, top + n as f32 * line_h + font.ascent());
+    }
+}
+
+/// Parsed once, rather than every time the window is redrawn
+fn font() -> &'static FontRef<'static> {
+    static FONT: OnceLock<FontRef> = OnceLock::new();
+    FONT.get_or_init(|| FontRef::try_from_slice(include_bytes!("../fonts/Inconsolata-Black.ttf")).unwrap())
+}
+
+/// Draw `text` starting at `x`, on the given `baseline`.
+fn draw_text(image: &mut RgbImage, font: &PxScaleFont<&FontRef>, text: &str, mut x: f32, baseline: f32) {
+    let mut previous = None;
+    for c in text.chars() {
+        let id = font.glyph_id(c);
+        if let Some(previous) = previous { x += font.kern(previous, id); }
+        let glyph = id.with_scale_and_position(font.scale(), point(x, baseline));
+        if let Some(outline) = font.outline_glyph(glyph) {
+            let bounds = outline.px_bounds();
+            outline.draw(|gx, gy, coverage| {
+                let (px, py) = (bounds.min.x as i32 + gx as i32, bounds.min.y as i32 + gy as i32);
+                if px >= 0 && py >= 0 && (px as u32) < image.width() && (py as u32) < image.height() {
+                    blend(image.get_pixel_mut(px as u32, py as u32), TEXT, coverage.min(1.0));
+                }
+            });
+        }
+        x += font.h_advance(id);
+        previous = Some(id);
+    }
+}
+
+fn text_width(font: &impl Font, scale: PxScale, text: &str) -> f32 {
+    let font = font.as_scaled(scale);
+    text.chars().map(|c| font.h_advance(font.glyph_id(c))).sum()
+}
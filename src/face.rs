@@ -0,0 +1,62 @@
+use std::cell::RefCell;
+
+use image::{DynamicImage, GenericImageView, imageops::FilterType};
+use rustface::{Detector, ImageData};
+
+const MODEL: &[u8] = include_bytes!("../models/seeta_fd_frontal_v1.0.bin");
+
+/// Detection is run on a copy of the image whose longest side is at most this
+/// many pixels: faces in class photos are large, so nothing is lost, and it is
+/// much faster than working on the full resolution.
+const DETECTION_SIZE: u32 = 600;
+
+thread_local! {
+    static DETECTOR: RefCell<Box<dyn Detector>> = RefCell::new(new_detector());
+}
+
+fn new_detector() -> Box<dyn Detector> {
+    let model = rustface::read_model(MODEL).expect("Embedded face detection model is corrupt");
+    let mut detector = rustface::create_detector_with_model(model);
+    detector.set_min_face_size(40);
+    detector.set_score_thresh(2.0);
+    detector.set_pyramid_scale_factor(0.8);
+    detector.set_slide_window_step(4, 4);
+    detector
+}
+
+/// Bounding box of a face, in pixels of the image in which it was found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Face {
+    /// centre
+    pub x: i32,
+    /// centre
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+}
+
+/// Find the most prominent (largest) face in `image`, if any.
+pub fn detect(image: &DynamicImage) -> Option<Face> {
+    let (w, h) = image.dimensions();
+    let gray = if w.max(h) > DETECTION_SIZE {
+        image.resize(DETECTION_SIZE, DETECTION_SIZE, FilterType::Triangle).to_luma8()
+    } else {
+        image.to_luma8()
+    };
+    let (sw, sh) = gray.dimensions();
+    let scale = sw as f32 / w as f32;
+
+    let faces = DETECTOR.with(|d| d.borrow_mut().detect(&ImageData::new(&gray, sw, sh)));
+    let biggest = faces
+        .iter()
+        .map(|f| *f.bbox())
+        .max_by_key(|b| b.width() * b.height())?;
+
+    let unscale = |n: f32| (n / scale).round() as i32;
+    Some(Face {
+        x: unscale(biggest.x() as f32 + biggest.width () as f32 / 2.0),
+        y: unscale(biggest.y() as f32 + biggest.height() as f32 / 2.0),
+        w: unscale(biggest.width () as f32),
+        h: unscale(biggest.height() as f32),
+    })
+}
@@ -0,0 +1,67 @@
+/// Undo and redo stacks for the states of a collection of items, each entry
+/// remembering which item it belongs to, so that changes can be undone after
+/// moving on to other items.
+#[derive(Debug)]
+pub struct History<S> {
+    undo: Vec<(usize, S)>,
+    redo: Vec<(usize, S)>,
+}
+
+impl<S> Default for History<S> {
+    fn default() -> Self { Self { undo: vec![], redo: vec![] } }
+}
+
+impl<S> History<S> {
+    /// Remember that item `n` was in state `before` prior to a new change.
+    pub fn record(&mut self, n: usize, before: S) {
+        self.undo.push((n, before));
+        self.redo.clear();
+    }
+
+    /// The item to be changed back and the state it should get, if there is
+    /// anything to undo. `current` gives the present state of an item, so that
+    /// the undo can itself be redone.
+    pub fn undo(&mut self, current: impl FnOnce(usize) -> S) -> Option<(usize, S)> {
+        let (n, state) = self.undo.pop()?;
+        self.redo.push((n, current(n)));
+        Some((n, state))
+    }
+
+    /// Like `undo`, but for undone changes.
+    pub fn redo(&mut self, current: impl FnOnce(usize) -> S) -> Option<(usize, S)> {
+        let (n, state) = self.redo.pop()?;
+        self.undo.push((n, current(n)));
+        Some((n, state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn undo_and_redo_across_items() {
+        let mut items = vec![0, 0];
+        let mut history = History::default();
+        macro_rules! change {
+            ($n:expr, $new:expr) => { history.record($n, items[$n]); items[$n] = $new; };
+        }
+        macro_rules! undo { () => {{ let (n, s) = history.undo(|n| items[n]).unwrap(); items[n] = s; n }} }
+        macro_rules! redo { () => {{ let (n, s) = history.redo(|n| items[n]).unwrap(); items[n] = s; n }} }
+
+        change!(0, 1);
+        change!(1, 5);
+        change!(0, 2);
+        assert_eq!(undo!(), 0); assert_eq!(items, [1, 5]);
+        assert_eq!(undo!(), 1); assert_eq!(items, [1, 0]);
+        assert_eq!(redo!(), 1); assert_eq!(items, [1, 5]);
+        assert_eq!(undo!(), 1);
+        assert_eq!(undo!(), 0); assert_eq!(items, [0, 0]);
+        assert!(history.undo(|n| items[n]).is_none());
+
+        assert_eq!(redo!(), 0); assert_eq!(items, [1, 0]);
+        change!(1, 7); // A new change forgets what was undone
+        assert!(history.redo(|n| items[n]).is_none());
+    }
+}
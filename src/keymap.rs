@@ -0,0 +1,325 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use show_image::event::{ModifiersState, VirtualKeyCode};
+
+/// Name of each user's own key bindings file, in their configuration directory
+pub const KEYMAP_FILE: &str = "keys.toml";
+
+/// Something the cropper can be asked to do from the keyboard
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    Quit,
+    Help,
+    Up,
+    Down,
+    Left,
+    Right,
+    ZoomIn,
+    ZoomOut,
+    Rotate,
+    Undo,
+    Redo,
+    ToggleView,
+    CycleGuides,
+    Accept,
+    Previous,
+    Next,
+    NextUnreviewed,
+    ToggleExcluded,
+    EditNames,
+    BrightnessDown,
+    BrightnessUp,
+    ContrastDown,
+    ContrastUp,
+    GammaDown,
+    GammaUp,
+    Cooler,
+    Warmer,
+    ResetAdjustments,
+}
+
+/// Every action, with its name in the keymap file, its default keys and what
+/// it does, in the order in which they are listed by the help overlay
+const ACTIONS: &[(Action, &str, &[VirtualKeyCode], &str)] = {
+    use Action::*;
+    use VirtualKeyCode as K;
+    &[
+        (Quit            , "quit"             , &[K::Escape], "save everything and quit"),
+        (Help            , "help"             , &[K::F1    ], "show or hide this help"),
+        (Up              , "up"               , &[K::Up    ], "move the image up"),
+        (Down            , "down"             , &[K::Down  ], "move the image down"),
+        (Left            , "left"             , &[K::Left  ], "move the image left"),
+        (Right           , "right"            , &[K::Right ], "move the image right"),
+        (ZoomIn          , "zoom-in"          , &[K::G     ], "zoom in"),
+        (ZoomOut         , "zoom-out"         , &[K::P     ], "zoom out"),
+        (Rotate          , "rotate"           , &[K::R     ], "turn the photo a quarter turn clockwise"),
+        (Undo            , "undo"             , &[K::Z     ], "undo"),
+        (Redo            , "redo"             , &[K::Y     ], "redo"),
+        (ToggleView      , "toggle-view"      , &[K::V     ], "switch between the crop and the whole photo"),
+        (CycleGuides     , "cycle-guides"     , &[K::L     ], "change the guide lines"),
+        (Accept          , "accept"           , &[K::Return], "mark as reviewed and go to the next face"),
+        (Previous        , "previous"         , &[K::Back  ], "previous face"),
+        (Next            , "next"             , &[K::Space ], "next face"),
+        (NextUnreviewed  , "next-unreviewed"  , &[K::N     ], "next face still to be reviewed"),
+        (ToggleExcluded  , "toggle-excluded"  , &[K::X     ], "leave out of the PDFs, or put back"),
+        (EditNames       , "edit-names"       , &[K::F2    ], "edit the names"),
+        (BrightnessDown  , "brightness-down"  , &[K::Key1  ], "darker"),
+        (BrightnessUp    , "brightness-up"    , &[K::Key2  ], "brighter"),
+        (ContrastDown    , "contrast-down"    , &[K::Key3  ], "less contrast"),
+        (ContrastUp      , "contrast-up"      , &[K::Key4  ], "more contrast"),
+        (GammaDown       , "gamma-down"       , &[K::Key5  ], "darker midtones"),
+        (GammaUp         , "gamma-up"         , &[K::Key6  ], "lighter midtones"),
+        (Cooler          , "cooler"           , &[K::Key7  ], "cooler colours"),
+        (Warmer          , "warmer"           , &[K::Key8  ], "warmer colours"),
+        (ResetAdjustments, "reset-adjustments", &[K::Key0  ], "undo manual corrections"),
+    ]
+};
+
+/// Keys which can be bound, by the names used in the keymap file
+const KEYS: &[VirtualKeyCode] = {
+    use VirtualKeyCode::*;
+    &[
+        Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0,
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+        F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+        Escape, Insert, Home, Delete, End, PageDown, PageUp, Left, Up, Right, Down,
+        Back, Return, Space, Tab,
+        Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+        NumpadAdd, NumpadSubtract, NumpadMultiply, NumpadDivide, NumpadDecimal, NumpadEnter,
+        Apostrophe, Backslash, Comma, Equals, Grave, LBracket, Minus, Period, Plus, RBracket, Semicolon, Slash,
+    ]
+};
+
+/// Name of `key` in the keymap file: as in `VirtualKeyCode`, except for the
+/// digits, which are just themselves
+pub fn key_name(key: VirtualKeyCode) -> String {
+    let name = format!("{key:?}");
+    match name.strip_prefix("Key") {
+        Some(digit) if digit.len() == 1 => digit.into(),
+        _ => name,
+    }
+}
+
+fn key_named(name: &str) -> Option<VirtualKeyCode> {
+    KEYS.iter().copied().find(|&key| key_name(key).eq_ignore_ascii_case(name))
+}
+
+/// A modifier key which changes the size of the steps
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Modifier { Shift, Ctrl, Alt, Logo }
+
+impl Modifier {
+    fn is_held(self, modifiers: ModifiersState) -> bool {
+        modifiers.contains(match self {
+            Modifier::Shift => ModifiersState::SHIFT,
+            Modifier::Ctrl  => ModifiersState::CTRL,
+            Modifier::Alt   => ModifiersState::ALT,
+            Modifier::Logo  => ModifiersState::LOGO,
+        })
+    }
+}
+
+/// How far the keys move and zoom the crop: the `[steps]` table
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Steps {
+    /// Pixels of the full size photo by which each key press moves or zooms
+    pub pixels: f64,
+    /// Multiplies the step while `fine_modifier` is held
+    pub fine: f64,
+    /// Multiplies the step while `coarse_modifier` is held
+    pub coarse: f64,
+    pub fine_modifier: Modifier,
+    pub coarse_modifier: Modifier,
+}
+
+impl Default for Steps {
+    fn default() -> Self {
+        Self { pixels: 10.0, fine: 0.1, coarse: 5.0, fine_modifier: Modifier::Ctrl, coarse_modifier: Modifier::Shift }
+    }
+}
+
+impl Steps {
+    fn check(&self) -> Result<(), String> {
+        for (name, value) in [("pixels", self.pixels), ("fine", self.fine), ("coarse", self.coarse)] {
+            if !value.is_finite() || value <= 0.0 {
+                return Err(format!("`{name}` in [steps] must be a number greater than 0, not {value}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Layout of the keymap file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct KeymapFile {
+    steps: Steps,
+    /// Keys for each action, by the action's name, replacing its default keys
+    keys: BTreeMap<String, Vec<String>>,
+}
+
+/// Which keys do what in the cropper
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    /// The keys of each action, in the order of `ACTIONS`
+    bindings: Vec<(Action, Vec<VirtualKeyCode>)>,
+    pub steps: Steps,
+    /// Where the bindings were read from, if they are not the defaults
+    pub source: Option<PathBuf>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self {
+            bindings: ACTIONS.iter().map(|&(action, _, keys, _)| (action, keys.to_vec())).collect(),
+            steps: Steps::default(),
+            source: None,
+        }
+    }
+}
+
+impl Keymap {
+    /// The current user's key bindings, or the defaults if they have none.
+    pub fn load() -> Self {
+        let Some(path) = user_keymap_path() else { return Self::default() };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(err) => panic!("Could not read {}: {err}", path.display()),
+        };
+        let keymap = Self::parse(&text)
+            .unwrap_or_else(|err| panic!("Error in {}:\n{err}", path.display()));
+        println!("Using the key bindings in {}", path.display());
+        Self { source: Some(path), ..keymap }
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let file: KeymapFile = toml::from_str(text).map_err(|err| err.to_string())?;
+        file.steps.check()?;
+        let mut keymap = Self { steps: file.steps, ..Self::default() };
+        for (action_name, key_names) in file.keys {
+            let Some(&(action, ..)) = ACTIONS.iter().find(|(_, name, ..)| *name == action_name) else {
+                let names = ACTIONS.iter().map(|(_, name, ..)| *name).collect::<Vec<_>>();
+                return Err(format!("unknown action `{action_name}`, expected one of: {}", names.join(", ")));
+            };
+            let keys = key_names.iter()
+                .map(|name| key_named(name).ok_or_else(|| format!("unknown key `{name}` for `{action_name}`")))
+                .collect::<Result<Vec<_>, _>>()?;
+            keymap.bindings.iter_mut().find(|(a, _)| *a == action).unwrap().1 = keys;
+        }
+        keymap.check_conflicts()?;
+        Ok(keymap)
+    }
+
+    fn check_conflicts(&self) -> Result<(), String> {
+        for (n, (action, keys)) in self.bindings.iter().enumerate() {
+            for key in keys {
+                if let Some((other, _)) = self.bindings[n + 1..].iter().find(|(_, k)| k.contains(key)) {
+                    return Err(format!("`{}` is bound to both `{}` and `{}`", key_name(*key), name(*action), name(*other)));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The action bound to `key`, if any
+    pub fn action(&self, key: VirtualKeyCode) -> Option<Action> {
+        self.bindings.iter().find(|(_, keys)| keys.contains(&key)).map(|&(action, _)| action)
+    }
+
+    /// Factor by which the step changes with the modifiers being held
+    pub fn multiplier(&self, modifiers: ModifiersState) -> f64 {
+        let Steps { fine, coarse, fine_modifier, coarse_modifier, .. } = self.steps;
+        let mut multiplier = 1.0;
+        if fine_modifier  .is_held(modifiers) { multiplier *= fine;   }
+        if coarse_modifier.is_held(modifiers) { multiplier *= coarse; }
+        multiplier
+    }
+
+    /// One line per action, for the help overlay
+    pub fn help(&self) -> Vec<String> {
+        let keys = self.bindings.iter()
+            .map(|(_, keys)| keys.iter().map(|&key| key_name(key)).collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>();
+        let width = keys.iter().map(|k| k.chars().count()).max().unwrap_or(0);
+        let Steps { pixels, fine, coarse, fine_modifier, coarse_modifier } = self.steps;
+        let mut lines = vec![
+            match &self.source {
+                Some(path) => format!("Keys from {}", path.display()),
+                None => match user_keymap_path() {
+                    Some(path) => format!("Default keys, which can be changed in {}", path.display()),
+                    None       => "Default keys".into(),
+                },
+            },
+            format!("Steps of {pixels} px, ×{fine} with {fine_modifier:?}, ×{coarse} with {coarse_modifier:?}"),
+            String::new(),
+        ];
+        for ((action, _), keys) in self.bindings.iter().zip(keys) {
+            lines.push(format!("{keys:>width$}  {}", description(*action)));
+        }
+        lines
+    }
+}
+
+fn name(action: Action) -> &'static str {
+    ACTIONS.iter().find(|(a, ..)| *a == action).unwrap().1
+}
+
+fn description(action: Action) -> &'static str {
+    ACTIONS.iter().find(|(a, ..)| *a == action).unwrap().3
+}
+
+/// Where each user keeps their own key bindings, as keyboards and habits differ
+pub fn user_keymap_path() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).map(PathBuf::from);
+    let config_dir = var("XDG_CONFIG_HOME")
+        .or_else(|| var("APPDATA"))
+        .or_else(|| var("HOME").map(|home| home.join(".config")))?;
+    Some(config_dir.join("trombinoscope").join(KEYMAP_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn defaults_do_not_conflict() {
+        assert_eq!(Keymap::default().check_conflicts(), Ok(()));
+        assert_eq!(Keymap::default().action(VirtualKeyCode::G), Some(Action::ZoomIn));
+    }
+
+    #[test]
+    fn file_replaces_the_keys_of_the_actions_it_mentions() {
+        let keymap = Keymap::parse(r#"
+            [steps]
+            pixels = 4
+            fine-modifier = "alt"
+            [keys]
+            up = ["w", "Up"]
+            brightness-up = ["9"]
+        "#).unwrap();
+        assert_eq!(keymap.action(VirtualKeyCode::W   ), Some(Action::Up));
+        assert_eq!(keymap.action(VirtualKeyCode::Up  ), Some(Action::Up));
+        assert_eq!(keymap.action(VirtualKeyCode::Key9), Some(Action::BrightnessUp));
+        assert_eq!(keymap.action(VirtualKeyCode::Key2), None);
+        assert_eq!(keymap.action(VirtualKeyCode::G   ), Some(Action::ZoomIn));
+        assert_eq!(keymap.steps.pixels, 4.0);
+        assert_eq!(keymap.multiplier(ModifiersState::ALT ), 0.1);
+        assert_eq!(keymap.multiplier(ModifiersState::CTRL), 1.0);
+    }
+
+    #[test]
+    fn conflicts_and_unknown_names_are_errors() {
+        assert!(Keymap::parse("[keys]\nzoom-in = [\"Z\"]").unwrap_err().contains("bound to both"));
+        assert!(Keymap::parse("[keys]\nzoom-in = [\"Hyper\"]").unwrap_err().contains("unknown key"));
+        assert!(Keymap::parse("[keys]\nzoom = [\"G\"]").unwrap_err().contains("unknown action"));
+        for steps in ["pixels = 0", "fine = -0.5", "coarse = 0.0", "coarse = nan"] {
+            assert!(Keymap::parse(&format!("[steps]\n{steps}")).unwrap_err().contains("greater than 0"), "{steps}");
+        }
+    }
+}
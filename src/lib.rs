@@ -1,3 +1,13 @@
 pub mod typst;
+pub mod adjust;
+pub mod colour;
+pub mod config;
 pub mod crop;
+pub mod display;
+pub mod face;
+pub mod history;
+pub mod keymap;
+pub mod loader;
+pub mod metadata;
+pub mod orientation;
 pub mod util;
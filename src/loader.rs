@@ -0,0 +1,267 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::thread;
+use std::time::Instant;
+
+use image::{DynamicImage, ImageResult};
+
+use crate::colour;
+use crate::face::{self, Face};
+use crate::orientation::Orientation;
+
+/// Longest side of the reduced copies of the photos which are kept in memory
+/// for display. Large enough for the crop to look decent in the cropper's
+/// window, small enough for cropping and the full view to be quick.
+pub const PROXY_SIZE: u32 = 1600;
+
+/// What to send back once a photo has been decoded
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Keep {
+    /// Just the face, if one was looked for
+    Nothing,
+    /// A copy reduced to `PROXY_SIZE`
+    Proxy,
+    /// The image at full resolution
+    Full,
+}
+
+/// A photo to be decoded in the background
+#[derive(Debug)]
+pub struct Job {
+    /// Identifies the photo when its result comes back
+    pub n: usize,
+    pub path: PathBuf,
+    pub orientation: Orientation,
+    /// Size of the image once `orientation` has been applied
+    pub size: (u32, u32),
+    /// Look for a face in the image
+    pub detect: bool,
+    pub keep: Keep,
+}
+
+/// The outcome of a `Job`
+#[derive(Debug)]
+pub struct Loaded {
+    pub n: usize,
+    /// The orientation which was applied to `image` and in which `face` was found
+    pub orientation: Orientation,
+    /// Full size or reduced, according to the job's `keep`
+    pub image: Option<DynamicImage>,
+    /// Whether the photo could be decoded: if not, `image` is blank
+    pub decoded: bool,
+    /// In the coordinates of the full size image
+    pub face: Option<Face>,
+}
+
+#[derive(Default)]
+struct Queue { jobs: VecDeque<Job>, closed: bool }
+
+/// Jobs waiting for a worker, and the means to wake idle workers
+type Shared = (Mutex<Queue>, Condvar);
+
+/// Decodes photos on a pool of background threads, in order of preference
+/// which can be changed at any time.
+pub struct Loader {
+    shared: Arc<Shared>,
+    results: mpsc::Receiver<Loaded>,
+    /// Photos which have been requested, but not yet received
+    pending: HashSet<usize>,
+    /// Number of worker threads
+    threads: usize,
+}
+
+impl Default for Loader {
+    fn default() -> Self { Self::new() }
+}
+
+impl Loader {
+    pub fn new() -> Self {
+        let shared = Arc::new(Shared::default());
+        let (sender, results) = mpsc::channel();
+        let threads = thread::available_parallelism().map_or(4, usize::from);
+        for _ in 0..threads {
+            let shared = Arc::clone(&shared);
+            let sender = sender.clone();
+            thread::spawn(move || work(&shared, &sender));
+        }
+        Self { shared, results, pending: HashSet::new(), threads }
+    }
+
+    /// Replace the jobs which have not been started yet with `jobs`, to be
+    /// started in the given order. Photos already being worked on are not
+    /// requested again.
+    pub fn request(&mut self, jobs: impl IntoIterator<Item = Job>) {
+        let (queue, ready) = &*self.shared;
+        let mut queue = queue.lock().unwrap();
+        for job in queue.jobs.drain(..) { self.pending.remove(&job.n); }
+        for job in jobs {
+            if self.pending.insert(job.n) { queue.jobs.push_back(job); }
+        }
+        ready.notify_all();
+    }
+
+    /// Add `jobs` after those which are already waiting.
+    fn add(&mut self, jobs: impl IntoIterator<Item = Job>) {
+        let (queue, ready) = &*self.shared;
+        let mut queue = queue.lock().unwrap();
+        for job in jobs {
+            if self.pending.insert(job.n) { queue.jobs.push_back(job); }
+        }
+        ready.notify_all();
+    }
+
+    /// Carry out all `jobs`, handing each result to `receive` as it arrives.
+    /// Only a few jobs are requested at a time, so that the images waiting to
+    /// be received cannot pile up, however many jobs there are.
+    pub fn run_all(&mut self, jobs: impl IntoIterator<Item = Job>, mut receive: impl FnMut(Loaded)) {
+        let mut jobs = jobs.into_iter();
+        loop {
+            let room = (2 * self.threads).saturating_sub(self.pending.len());
+            self.add(jobs.by_ref().take(room));
+            if !self.has_pending() { return }
+            receive(self.recv());
+        }
+    }
+
+    pub fn is_pending(&self, n: usize) -> bool { self.pending.contains(&n) }
+    pub fn has_pending(&self) -> bool { !self.pending.is_empty() }
+
+    /// A finished job, if there is one
+    pub fn try_recv(&mut self) -> Option<Loaded> {
+        let loaded = self.results.try_recv().ok()?;
+        self.pending.remove(&loaded.n);
+        Some(loaded)
+    }
+
+    /// Wait for the next job to finish. There must be some pending.
+    pub fn recv(&mut self) -> Loaded {
+        assert!(self.has_pending(), "Waiting for images which were never requested");
+        let loaded = self.results.recv().expect("Image loading threads have died");
+        self.pending.remove(&loaded.n);
+        loaded
+    }
+}
+
+impl Drop for Loader {
+    fn drop(&mut self) {
+        let (queue, ready) = &*self.shared;
+        let mut queue = queue.lock().unwrap();
+        queue.jobs.clear();
+        queue.closed = true;
+        ready.notify_all();
+    }
+}
+
+fn work(shared: &Shared, results: &mpsc::Sender<Loaded>) {
+    let (queue, ready) = shared;
+    loop {
+        let job = {
+            let queue = queue.lock().unwrap();
+            let mut queue = ready.wait_while(queue, |q| q.jobs.is_empty() && !q.closed).unwrap();
+            if queue.closed { return }
+            queue.jobs.pop_front().unwrap()
+        };
+        if results.send(load(job)).is_err() { return }
+    }
+}
+
+fn load(Job { n, path, orientation, size: (w, h), detect, keep }: Job) -> Loaded {
+    let start = Instant::now();
+    let (image, decoded) = match decode(&path, orientation) {
+        Ok(image) => {
+            println!("Loaded {} in {:.0?}", path.display(), start.elapsed());
+            (image, true)
+        },
+        Err(err) => {
+            println!("WARNING: could not decode {}, using a blank image instead: {err}", path.display());
+            (DynamicImage::new_rgb8(w, h), false)
+        },
+    };
+    let face = if detect {
+        let start = Instant::now();
+        let face = face::detect(&image);
+        match face {
+            Some(_) => println!("Found face in {} in {:.0?}", path.display(), start.elapsed()),
+            None    => println!("No face found in {}", path.display()),
+        }
+        face
+    } else { None };
+    let image = match keep {
+        Keep::Nothing => None,
+        // `thumbnail` would enlarge small photos
+        Keep::Proxy if image.width().max(image.height()) > PROXY_SIZE => Some(image.thumbnail(PROXY_SIZE, PROXY_SIZE)),
+        Keep::Proxy   => Some(image),
+        Keep::Full    => Some(image),
+    };
+    Loaded { n, orientation, image, decoded, face }
+}
+
+/// Decode the photo at `path` into sRGB and turn it the right way up.
+pub fn decode(path: impl AsRef<Path>, orientation: Orientation) -> ImageResult<DynamicImage> {
+    let bytes = std::fs::read(&path)?;
+    Ok(orientation.apply(colour::decode(&bytes, path.as_ref())?.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use pretty_assertions::assert_eq;
+
+    fn job(n: usize, path: &Path, keep: Keep) -> Job {
+        Job { n, path: path.into(), orientation: Orientation::default(), size: (40, 30), detect: false, keep }
+    }
+
+    #[test]
+    fn replaced_jobs_are_no_longer_pending() {
+        let dir = TestDir::new();
+        let paths = dir.photos(30);
+        let mut loader = Loader::new();
+        loader.request(paths.iter().enumerate().map(|(n, p)| job(n, p, Keep::Nothing)));
+        assert!(loader.is_pending(29));
+        // Those already started still arrive, the others are dropped
+        loader.request([job(29, &paths[29], Keep::Full)]);
+        let mut received = vec![];
+        while loader.has_pending() { received.push(loader.recv().n); }
+        assert!(received.contains(&29), "{received:?}");
+        let mut unique = received.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), received.len());
+        assert!(loader.try_recv().is_none());
+    }
+
+    #[test]
+    fn running_all_jobs_delivers_each_once() {
+        let dir = TestDir::new();
+        let paths = dir.photos(30);
+        let mut loader = Loader::new();
+        let mut received = vec![];
+        loader.run_all(paths.iter().enumerate().map(|(n, p)| job(n, p, Keep::Full)), |loaded| {
+            assert!(loaded.image.is_some());
+            received.push(loaded.n);
+        });
+        received.sort();
+        assert_eq!(received, (0..30).collect::<Vec<_>>());
+        assert!(!loader.has_pending());
+    }
+
+    #[test]
+    fn images_are_kept_as_requested() {
+        let dir = TestDir::new();
+        let paths = dir.photos(3);
+        let mut loader = Loader::new();
+        loader.request([
+            job(0, &paths[0], Keep::Nothing),
+            job(1, &paths[1], Keep::Proxy  ),
+            job(2, &paths[2], Keep::Full   ),
+        ]);
+        let mut kept = [None, None, None];
+        while loader.has_pending() {
+            let Loaded { n, image, .. } = loader.recv();
+            kept[n] = Some(image.map(|image| image.width()));
+        }
+        assert_eq!(kept, [Some(None), Some(Some(40)), Some(Some(40))]);
+    }
+}
@@ -6,9 +6,12 @@ use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 use clap::Parser;
-use show_image::create_window;
+use show_image::{create_window, WindowOptions};
 
-use trombinoscope::crop::{crop_interactively, write_cropped_images, Cropped};
+use trombinoscope::config::ClassConfig;
+use trombinoscope::crop::{crop_interactively, detect_faces, disambiguate_output_names, normalise_exposure, write_cropped_images, AspectRatio, Cropped, Output, OutputFormat};
+use trombinoscope::keymap::Keymap;
+use trombinoscope::metadata::{self, MetadataStore, Storage};
 use typst::foundations::Smart;
 use typst::eval::Tracer;
 
@@ -22,60 +25,159 @@ use trombinoscope::typst::TypstWrapperWorld;
 struct Cli {
     /// Directory containing the class assets
     class_dir: PathBuf,
+
+    /// Move the class' crops and names to a different storage, and use that
+    /// storage from now on
+    #[arg(long, value_name = "STORAGE")]
+    convert_to: Option<Storage>,
+
+    /// Generate the PDFs from the stored crops, without opening a window
+    #[arg(long)]
+    batch: bool,
+
+    /// Make the brightness and skin tones of all faces consistent, before
+    /// cropping or rendering. The corrections can be overridden in the cropper.
+    #[arg(long)]
+    normalise: bool,
 }
 
-#[show_image::main]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
+    if let Some(target) = cli.convert_to {
+        convert_storage(&cli.class_dir, target);
+        return Ok(());
+    }
+
+    if cli.batch {
+        return render_from_stored_crops(cli);
+    }
+
+    // Only now do we need a display
+    show_image::run_context(move || crop_and_render(cli))
+}
+
+fn convert_storage(class_dir: impl AsRef<Path>, target: Storage) {
+    let full_photo_dir = class_dir.as_ref().join("Complet");
+    let config = ClassConfig::load(&class_dir);
+    if target == config.storage {
+        println!("Metadata already stored as {target:?}");
+        return;
+    }
+    let photos = find_photos_in_dir(&full_photo_dir);
+    metadata::convert(
+        &photos,
+        &*config.storage.open(&full_photo_dir),
+        &*target        .open(&full_photo_dir),
+    );
+    ClassConfig::set_storage(&class_dir, target);
+    println!("Metadata now stored as {target:?}");
+}
+
+fn crop_and_render(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let full_photo_dir = cli.class_dir.join("Complet");
+    let config = ClassConfig::load(&cli.class_dir);
+    let store = config.storage.open(&full_photo_dir);
+    let keymap = Keymap::load();
+
+    let mut faces = load_faces(&full_photo_dir, &*store, &config)?;
+    if cli.normalise { normalise_exposure(&mut faces, &*store); }
+
+    // The default controls pan and zoom the view with the mouse, which we use for cropping
+    let options = WindowOptions::new().set_default_controls(false);
+    let window = create_window("image", options)?;
+    crop_interactively(&mut faces, &*store, &window, &keymap).unwrap();
+
+    render_all(&faces, cli.class_dir, &config);
+    Ok(())
+}
+
+fn render_from_stored_crops(cli: Cli) -> Result<(), Box<dyn std::error::Error>> {
     let full_photo_dir = cli.class_dir.join("Complet");
-    let render_dir     = cli.class_dir.join("Recadré");
+    let config = ClassConfig::load(&cli.class_dir);
+    let store = config.storage.open(&full_photo_dir);
+
+    let mut faces = load_faces(&full_photo_dir, &*store, &config)?;
+    if cli.normalise { normalise_exposure(&mut faces, &*store); }
+    detect_faces(&mut faces);
 
+    render_all(&faces, &cli.class_dir, &config);
+
+    let uncropped = faces.iter().filter(|f| !f.has_been_cropped() && !f.is_excluded()).collect::<Vec<_>>();
+    if !uncropped.is_empty() {
+        println!("\nWARNING: {} images have never been cropped, their crops were guessed:", uncropped.len());
+        for face in uncropped {
+            println!("    {}", face.path.display());
+        }
+    }
+    Ok(())
+}
+
+fn load_faces(
+    photo_dir: impl AsRef<Path>,
+    store: &dyn MetadataStore,
+    config: &ClassConfig,
+) -> std::io::Result<Vec<Cropped>> {
     let start = Instant::now();
-    let mut faces = std::fs::read_dir(full_photo_dir)?
-        .take(100)
+    let mut photos = std::fs::read_dir(photo_dir)?
         .filter_map(|x| x.ok())
         .map(|p| p.path())
-        .filter_map(Cropped::load)
+        .filter(|p| is_photo(p))
         .collect::<Vec<_>>();
-    println!("Loading all images took {:.1?}", start.elapsed());
-
-    let window = create_window("image", Default::default())?;
-    crop_interactively(&mut faces, &window).unwrap();
+    photos.sort();
+    config.limit_photos(&mut photos);
+    let mut faces = photos
+        .into_iter()
+        .filter_map(|p| {
+            let face = Cropped::load(&p, store, config.aspect_ratio);
+            if face.is_none() { println!("WARNING: ignoring {}, which could not be read", p.display()); }
+            face
+        })
+        .collect::<Vec<_>>();
+    disambiguate_output_names(&mut faces);
+    println!("Reading metadata of all images took {:.1?}", start.elapsed());
+    Ok(faces)
+}
 
-    std::fs::create_dir_all(&render_dir).unwrap(); // Ensure it exists so next line works
-    std::fs::remove_dir_all(&render_dir).unwrap(); // Remove it and its contents
-    std::fs::create_dir_all(&render_dir).unwrap(); // Ensure it exists
+/// Write the cropped images and generate the PDFs from them
+fn render_all(faces: &[Cropped], class_dir: impl AsRef<Path>, config: &ClassConfig) {
+    let render_dir = class_dir.as_ref().join("Recadré");
 
-    write_cropped_images(&faces, &render_dir);
+    let output = Output { width: output_width(&config.output), ..config.output };
+    write_cropped_images(faces, &render_dir, &output);
 
-    trombinoscope(render_dir, cli.class_dir);
+    trombinoscope(faces, render_dir, class_dir, output.format, config.aspect_ratio);
 
-    Ok(())
+    let excluded = faces.iter().filter(|f| f.is_excluded()).collect::<Vec<_>>();
+    if !excluded.is_empty() {
+        println!("\n{} students were left out:", excluded.len());
+        for face in excluded {
+            println!("    {} {}    ({})", face.given, face.family, face.path.display());
+        }
+    }
 }
 
-fn trombinoscope(render_dir: impl AsRef<Path>, class_dir: impl AsRef<Path>) {
+fn trombinoscope(
+    faces: &[Cropped],
+    render_dir: impl AsRef<Path>,
+    class_dir: impl AsRef<Path>,
+    format: OutputFormat,
+    ratio: AspectRatio,
+) {
 
-    let items = find_jpgs_in_dir(&render_dir)
+    let mut items = faces
         .iter()
-        .filter_map(path_to_item)
+        .filter(|f| !f.is_excluded())
+        .map(|f| face_to_item(f, format))
         .collect::<Vec<_>>();
 
-    let mut items = items.to_vec();
     items.sort_by(family_given);
 
     let class_name = class_from_dir(&class_dir);
-    render(trombi_typst_src(&items, &class_name) , &render_dir, &class_dir, FileType::Trombi);
+    render(trombi_typst_src(&items, &class_name, ratio), &render_dir, &class_dir, FileType::Trombi);
     render(labels_typst_src(&items, &class_name) , &render_dir, &class_dir, FileType::Labels);
 }
 
-fn write_cropped(in_file: impl AsRef<Path>, out_dir: impl AsRef<Path>) {
-    let cropped = Cropped::load(in_file).unwrap();
-    let out_file = out_dir.as_ref().join(cropped.path.file_name().unwrap());
-    println!("Writing {}", out_file.display());
-    cropped.write(out_file).unwrap();
-}
-
 fn render(
     content: String,
     render_dir: impl AsRef<Path>,
@@ -88,7 +190,7 @@ fn render(
     });
 
     let typst_src_path = render_dir.as_ref().join(&typst_src_filename);
-    let mut out = fs::File::create(&typst_src_path).unwrap();
+    let mut out = fs::File::create(typst_src_path).unwrap();
     out.write_all(content.as_bytes()).unwrap();
 
     // Create world with content.
@@ -123,11 +225,12 @@ fn render(
 
 fn labels_typst_src(items: &[Item], class_name: &str) -> String {
     let institution = "CO Montbrillant";
+    let class_name = typst_text(class_name);
 
     let label = |given, family| format!("label([{given}], [{family}])");
     let labels = items
         .iter()
-        .map(|i| label(i.name.given.clone(), i.name.family.clone()))
+        .map(|i| label(typst_text(&i.name.given), typst_text(&i.name.family)))
         .collect::<Vec<_>>()
         .join(",\n    ");
 
@@ -164,11 +267,16 @@ fn labels_typst_src(items: &[Item], class_name: &str) -> String {
 )"#}
 }
 
-fn trombi_typst_src(items: &[Item], class_name: &str) -> String {
+fn trombi_typst_src(items: &[Item], class_name: &str, ratio: AspectRatio) -> String {
+    let (ratio_h, ratio_w) = ratio.height_to_width();
+    let class_name = typst_text(class_name);
     let table_items = items
         .iter()
         .map(|Item { image, name: Name { given, family } }| {
-            format!("    item([{given}], [{family}], \"{image}\")", image=image.display())
+            format!("    item([{given}], [{family}], \"{image}\")",
+                    given  = typst_text(given),
+                    family = typst_text(family),
+                    image  = typst_string(&image.to_string_lossy()))
         })
         .collect::<Vec<_>>()
         .join(",\n");
@@ -193,9 +301,9 @@ fn trombi_typst_src(items: &[Item], class_name: &str) -> String {
     #text(family, stroke: none, fill: colF)
 ]
 
-#let n_columns = 6
-#let pic_w = 200mm / n_columns
-#let pic_h = pic_w * 3 / 2
+#let n_columns = {N_COLUMNS}
+#let pic_w = {TABLE_WIDTH_MM}mm / n_columns
+#let pic_h = pic_w * {ratio_h} / {ratio_w}
 
 #let item(given, family, path) = {{
     set rect(
@@ -228,6 +336,35 @@ fn trombi_typst_src(items: &[Item], class_name: &str) -> String {
 
 }
 
+/// `text` escaped so that it appears as is in Typst markup, rather than being
+/// taken for markup itself
+fn typst_text(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if "\\#[]*_$@<>`~=-+/".contains(c) { escaped.push('\\'); }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// `text` escaped for use inside a Typst string literal
+fn typst_string(text: &str) -> String { text.replace('\\', "\\\\").replace('"', "\\\"") }
+
+/// Number of pictures across the trombinoscope
+const N_COLUMNS: u32 = 6;
+/// Width of the trombinoscope's table of pictures
+const TABLE_WIDTH_MM: f32 = 200.0;
+const MM_PER_INCH: f32 = 25.4;
+
+/// Width in pixels of the cropped images, as given, or as needed to print them
+/// in the trombinoscope at the given resolution
+fn output_width(output: &Output) -> Option<u32> {
+    output.width.or_else(|| {
+        let dpi = output.dpi?;
+        Some((TABLE_WIDTH_MM / N_COLUMNS as f32 / MM_PER_INCH * dpi as f32).round() as u32)
+    })
+}
+
 fn class_from_dir(dir: impl AsRef<Path>) -> String {
     let std::path::Component::Normal(class) = dir.as_ref().components().last().unwrap()
         else { panic!("Last component of `{dir}` cannot be interpreted as a class name", dir = dir.as_ref().display()) };
@@ -252,31 +389,80 @@ fn family_given(l: &Item, r: &Item) -> Ordering {
     }
 }
 
-fn find_jpgs_in_dir(dir: impl AsRef<Path>) -> Vec<PathBuf> {
+fn find_photos_in_dir(dir: impl AsRef<Path>) -> Vec<PathBuf> {
     std::fs::read_dir(dir)
         .unwrap()
         .map(|res| res.map(|e| e.path()).unwrap())
-        .filter(|x| is_jpg(x)) // WTF: eta conversion leads to filter not implementing Iterator!
+        .filter(|x| is_photo(x)) // WTF: eta conversion leads to filter not implementing Iterator!
         .collect()
 }
 
-fn is_jpg(path: impl AsRef<Path>) -> bool {
-    if let Some(ref extension) = path.as_ref().extension() {
-        ["jpg", "jpeg", "JPG", "JPEG"]
-            .iter()
-            .map(OsStr::new)
-            .collect::<Vec<_>>()
-            .contains(extension)
+fn is_photo(path: impl AsRef<Path>) -> bool {
+    if let Some(extension) = path.as_ref().extension().and_then(OsStr::to_str) {
+        ["jpg", "jpeg", "png", "webp", "tif", "tiff"]
+            .contains(&extension.to_lowercase().as_str())
     } else {
         false
     }
 }
 
-fn path_to_item(image_path: impl AsRef<Path>) -> Option<Item> {
-    let basename = image_path.as_ref().file_name()?;
-    let (given, family) = trombinoscope::util::filename_to_given_family(&image_path)?;
-    Some( Item {
-        image: basename.into(),
-        name: Name { given, family }
-    })
+fn face_to_item(face: &Cropped, format: OutputFormat) -> Item {
+    Item {
+        image: face.output_name(format),
+        name: Name { given: face.given.clone(), family: face.family.clone() },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trombinoscope::util::TestDir;
+    use pretty_assertions::assert_eq;
+    use typst::layout::{Frame, FrameItem};
+
+    /// All the text on the pages of the document compiled from `source`,
+    /// whose images are in `dir`
+    fn compiled_text(source: String, dir: &Path) -> String {
+        fn collect(frame: &Frame, text: &mut String) {
+            for (_, item) in frame.items() {
+                match item {
+                    FrameItem::Group(group) => collect(&group.frame, text),
+                    FrameItem::Text (item ) => text.push_str(&item.text),
+                    _ => {},
+                }
+            }
+        }
+        let world = TypstWrapperWorld::new(dir.display().to_string(), source);
+        let document = typst::compile(&world, &mut Tracer::default()).unwrap();
+        let mut text = String::new();
+        for page in &document.pages { collect(&page.frame, &mut text); }
+        text
+    }
+
+    #[test]
+    fn output_width_is_given_or_fits_the_resolution() {
+        let output = |width, dpi| output_width(&Output { width, dpi, ..Output::default() });
+        assert_eq!(output(None     , None     ), None);
+        assert_eq!(output(Some(500), Some(300)), Some(500));
+        // A sixth of 200 mm, at 300 dots per inch
+        assert_eq!(output(None     , Some(300)), Some(394));
+    }
+
+    #[test]
+    fn names_and_paths_are_escaped() {
+        let dir = TestDir::new();
+        let image = PathBuf::from(r#"Zoé "\" Martin.png"#);
+        image::RgbImage::new(2, 3).save(dir.join(&image)).unwrap();
+        let given = r"#1 [*bold*] _x_ $y$ \ @ref <label> `raw` // - = + ~";
+        let items = [Item { image, name: Name { given: given.into(), family: "Martin".into() } }];
+        let without_spaces = |text: &str| text.replace(' ', "");
+
+        let trombi = compiled_text(trombi_typst_src(&items, "9*9", AspectRatio::default()), &dir);
+        assert!(without_spaces(&trombi).contains(&without_spaces(given)), "{trombi}");
+        assert!(trombi.contains("9*9"), "{trombi}");
+        let labels = compiled_text(labels_typst_src(&items, "9*9"), &dir);
+        assert!(without_spaces(&labels).contains(&without_spaces(given)), "{labels}");
+
+        assert_eq!(typst_string(r#"a "b" \c"#), r#"a \"b\" \\c"#);
+    }
 }
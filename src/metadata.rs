@@ -0,0 +1,702 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use bitcode::{self, Encode, Decode};
+use image::ImageFormat;
+use img_parts::jpeg::{self, JpegSegment, Jpeg};
+use img_parts::png::{Png, PngChunk};
+use serde::{Serialize, Deserialize};
+
+use crate::adjust::Adjustments;
+use crate::orientation::Orientation;
+
+/// Everything we remember about a photo between runs.
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct Metadata {
+    pub given: String,
+    pub family: String,
+    /// Where the crop lies in the photo, the right way up
+    pub crop: CropBox,
+    /// Height to width aspect ratio of the crop
+    pub r: (i32, i32),
+    /// Overrides the orientation found in the EXIF data
+    pub orientation: Option<Orientation>,
+    /// Whether someone has confirmed the crop
+    pub reviewed: bool,
+    /// Left out of the generated images and PDFs
+    pub excluded: bool,
+    /// Corrections chosen for this photo, which take precedence over
+    /// `default_adjustments`
+    pub adjustments: Option<Adjustments>,
+    /// Corrections found by normalising the exposure of the whole class
+    pub default_adjustments: Option<Adjustments>,
+}
+
+/// Position and size of a crop.
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Debug)]
+pub enum CropBox {
+    /// Centre and width, as fractions of the width and height of the photo, so
+    /// that the crop survives resizing the photo
+    Fraction { x: f64, y: f64, w: f64 },
+    /// Centre and width in pixels, as recorded by earlier versions
+    Pixels { x: i32, y: i32, w: i32 },
+}
+
+impl CropBox {
+    /// The crop with centre `(x, y)` and width `w`, in pixels of a photo of `size`
+    pub fn from_pixels((x, y, w): (f64, f64, f64), (width, height): (u32, u32)) -> Self {
+        // Rounded, so that converting to pixels and back gives exactly the same
+        // fractions, and unchanged crops are not taken to have changed
+        let fraction = |n: f64, of: u32| (n / of as f64 * 1e9).round() / 1e9;
+        CropBox::Fraction { x: fraction(x, width), y: fraction(y, height), w: fraction(w, width) }
+    }
+
+    /// Centre and width in pixels of a photo of `size`
+    pub fn in_pixels(self, (width, height): (u32, u32)) -> (f64, f64, f64) {
+        match self {
+            CropBox::Fraction { x, y, w } => (x * width as f64, y * height as f64, w * width as f64),
+            CropBox::Pixels   { x, y, w } => (x as f64, y as f64, w as f64),
+        }
+    }
+}
+
+/// Aspect ratio of every crop, before it was recorded
+const OLD_RATIO: (i32, i32) = (3, 2);
+
+/// Layout of the metadata written before they were labelled and versioned,
+/// when every image was turned with `rotate270`.
+#[derive(Encode, Decode)]
+struct MetadataV0 {
+    given: String,
+    family: String,
+    x: i32,
+    y: i32,
+    w: i32,
+}
+
+impl From<MetadataV0> for Metadata {
+    fn from(MetadataV0 { given, family, x, y, w }: MetadataV0) -> Self {
+        Self { given, family, crop: CropBox::Pixels { x, y, w }, r: OLD_RATIO, orientation: Orientation::from_exif(8), reviewed: false, excluded: false, adjustments: None, default_adjustments: None }
+    }
+}
+
+const OUR_MARKER: u8 = jpeg::markers::APP14;
+/// Identifies our segments among those written by other software (Adobe also
+/// uses APP14). Followed by a NUL, as is customary for APPn identifiers.
+const OUR_LABEL: &str = "trombinoscope";
+/// Type of the PNG chunk holding our metadata: ancillary, private and safe to
+/// copy, according to the case of its letters.
+const OUR_PNG_CHUNK: [u8; 4] = *b"trOm";
+/// Bump whenever the layout of `Metadata` changes, keeping the old layout
+/// around for migration.
+const FORMAT_VERSION: u8 = 1;
+
+/// Interpretation of a chunk of bytes which might contain our metadata.
+#[derive(PartialEq, Debug)]
+pub enum Decoded {
+    Current(Metadata),
+    /// Written by an earlier version of trombinoscope
+    Migrated(Metadata),
+    /// Labelled as ours, but unreadable
+    Corrupt(String),
+    /// Belongs to some other software
+    Foreign,
+}
+
+pub fn encode(metadata: &Metadata) -> Vec<u8> {
+    let mut bytes = Vec::from(OUR_LABEL.as_bytes());
+    bytes.push(0);
+    bytes.push(FORMAT_VERSION);
+    bytes.extend(bitcode::encode(metadata));
+    bytes
+}
+
+pub fn decode(bytes: &[u8]) -> Decoded {
+    use Decoded::*;
+    let labelled = bytes
+        .strip_prefix(OUR_LABEL.as_bytes())
+        .and_then(|rest| rest.strip_prefix(&[0]));
+    if let Some(rest) = labelled {
+        return match rest.split_first() {
+            Some((&FORMAT_VERSION, payload)) => bitcode::decode(payload)
+                .map_or_else(|err| Corrupt(err.to_string()), Current),
+            Some((version, _)) => Corrupt(format!("unknown format version {version}")),
+            None               => Corrupt("missing format version".into()),
+        };
+    }
+    if bytes.starts_with(b"Adobe") { return Foreign }
+    if let Ok(metadata) = bitcode::decode::<MetadataV0>(bytes) { return Migrated(metadata.into()) }
+    Foreign
+}
+
+type Parsed<T> = Result<T, String>;
+
+/// Parse `bytes` read from a photo, which may be malformed
+fn parse<T, E: std::fmt::Display>(bytes: std::io::Result<Vec<u8>>, parse: impl FnOnce(img_parts::Bytes) -> Result<T, E>) -> Parsed<T> {
+    parse(bytes.map_err(|err| err.to_string())?.into()).map_err(|err| err.to_string())
+}
+
+/// A photo at `path` which cannot be read or parsed is reported, and treated as
+/// if it had none of our metadata, rather than stopping everything.
+fn or_warn<T>(parsed: Parsed<T>, path: &Path) -> Option<T> {
+    parsed.map_err(|err| println!("WARNING: ignoring the metadata of {}: {err}", path.display())).ok()
+}
+
+fn write_jpeg(jpeg: Jpeg, sink: &mut impl Write) { jpeg.encoder().write_to(sink).unwrap(); }
+fn read_jpeg       (path: &Path) -> Parsed<Jpeg> { parse(std::fs::read(path), Jpeg::from_bytes) }
+fn read_jpeg_header(path: &Path) -> Parsed<Jpeg> { parse(read_header (path), Jpeg::from_bytes) }
+
+fn is_ours(segment: &JpegSegment) -> bool {
+    segment.marker() == OUR_MARKER && decode(segment.contents()) != Decoded::Foreign
+}
+
+/// Find our metadata among the segments of `jpeg`, which was read from `path`.
+///
+/// Problems are reported, but are not fatal: the photo is treated as if it had
+/// never been cropped.
+pub fn find_in_jpeg(jpeg: &Jpeg, path: impl AsRef<Path>) -> Option<Metadata> {
+    let path = path.as_ref().display();
+    let mut legacy = None;
+    for segment in jpeg.segments().iter().filter(|s| s.marker() == OUR_MARKER) {
+        match decode(segment.contents()) {
+            Decoded::Current(metadata) => return Some(metadata),
+            Decoded::Migrated(metadata) => { legacy.get_or_insert(metadata); },
+            Decoded::Corrupt(err) => println!("WARNING: ignoring corrupt metadata in {path}: {err}"),
+            Decoded::Foreign => {},
+        }
+    }
+    if legacy.is_some() { println!("Found old metadata in {path}, to be upgraded when next saved"); }
+    legacy
+}
+
+/// Replace our metadata in the JPEG file at `path`, leaving segments written by
+/// other software untouched.
+pub fn embed_in_jpeg_file(path: impl AsRef<Path>, metadata: &Metadata) {
+    let path = path.as_ref();
+    // Losing the metadata without notice would be worse
+    let mut jpeg = read_jpeg(path).unwrap_or_else(|err| panic!("Could not save the metadata of {}: {err}", path.display()));
+    let all_segments = jpeg.segments_mut();
+    let new_segment = JpegSegment::new_with_contents(
+        OUR_MARKER,
+        img_parts::Bytes::from(encode(metadata)),
+    );
+    if let Some(pos) = all_segments.iter().position(is_ours) {
+        all_segments.retain(|seg| !is_ours(seg));
+        all_segments.insert(pos, new_segment);
+    } else {
+        let new_pos = all_segments.len() - 1;
+        all_segments.insert(new_pos, new_segment);
+    };
+    let file = &mut std::fs::File::create(path).unwrap();
+    write_jpeg(jpeg, file);
+}
+
+/// Remove our metadata from the JPEG file at `path`, if it has any.
+pub fn strip_from_jpeg_file(path: impl AsRef<Path>) {
+    let Some(mut jpeg) = or_warn(read_jpeg(path.as_ref()), path.as_ref()) else { return };
+    if !jpeg.segments().iter().any(is_ours) { return }
+    jpeg.segments_mut().retain(|seg| !is_ours(seg));
+    let file = &mut std::fs::File::create(&path).unwrap();
+    write_jpeg(jpeg, file);
+}
+
+fn read_png       (path: &Path) -> Parsed<Png> { parse(std::fs::read(path), Png::from_bytes) }
+fn read_png_header(path: &Path) -> Parsed<Png> { parse(read_header (path), Png::from_bytes) }
+
+fn write_png(png: Png, path: impl AsRef<Path>) {
+    let file = &mut std::fs::File::create(&path).unwrap();
+    png.encoder().write_to(file).unwrap();
+}
+
+/// Find our metadata among the chunks of `png`, which was read from `path`.
+pub fn find_in_png(png: &Png, path: impl AsRef<Path>) -> Option<Metadata> {
+    decode_reporting(png.chunk_by_type(OUR_PNG_CHUNK)?.contents(), path)
+}
+
+/// Replace our metadata in the PNG file at `path`.
+pub fn embed_in_png_file(path: impl AsRef<Path>, metadata: &Metadata) {
+    let path = path.as_ref();
+    let mut png = read_png(path).unwrap_or_else(|err| panic!("Could not save the metadata of {}: {err}", path.display()));
+    png.remove_chunks_by_type(OUR_PNG_CHUNK);
+    let chunks = png.chunks_mut();
+    // Before the image data, so that it can be found without reading them
+    let pos = chunks.iter().position(|c| c.kind() == *b"IDAT").unwrap_or(chunks.len() - 1);
+    chunks.insert(pos, PngChunk::new(OUR_PNG_CHUNK, img_parts::Bytes::from(encode(metadata))));
+    write_png(png, path);
+}
+
+/// Remove our metadata from the PNG file at `path`, if it has any.
+pub fn strip_from_png_file(path: impl AsRef<Path>) {
+    let Some(mut png) = or_warn(read_png(path.as_ref()), path.as_ref()) else { return };
+    if png.chunk_by_type(OUR_PNG_CHUNK).is_none() { return }
+    png.remove_chunks_by_type(OUR_PNG_CHUNK);
+    write_png(png, path);
+}
+
+/// The bytes of a photo, less any metadata of ours embedded in it: what is left
+/// only changes when the image itself does.
+pub fn image_content(bytes: &[u8]) -> Vec<u8> {
+    match image::guess_format(bytes) {
+        Ok(ImageFormat::Jpeg) => if let Ok(mut jpeg) = Jpeg::from_bytes(bytes.to_owned().into()) {
+            jpeg.segments_mut().retain(|seg| !is_ours(seg));
+            return jpeg.encoder().bytes().into()
+        },
+        Ok(ImageFormat::Png) => if let Ok(mut png) = Png::from_bytes(bytes.to_owned().into()) {
+            png.remove_chunks_by_type(OUR_PNG_CHUNK);
+            return png.encoder().bytes().into()
+        },
+        _ => {},
+    }
+    bytes.to_owned()
+}
+
+const PNG_SIGNATURE: [u8; 8] = *b"\x89PNG\r\n\x1a\n";
+const PNG_IEND: [u8; 12] = *b"\0\0\0\0IEND\xae\x42\x60\x82";
+
+/// The beginning of the photo at `path`, up to where its image data starts:
+/// enough for its size, EXIF data and our metadata, without reading the rest,
+/// which is slow when the photos are on a network drive. The JPEG or PNG is
+/// ended right there, so that it can be parsed. Other formats are read in full.
+pub fn read_header(path: impl AsRef<Path>) -> std::io::Result<Vec<u8>> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut header = vec![];
+    // Appends the next `n` bytes of the file, returning where they start
+    fn read(file: &mut impl Read, header: &mut Vec<u8>, n: usize) -> std::io::Result<usize> {
+        let start = header.len();
+        header.resize(start + n, 0);
+        file.read_exact(&mut header[start..]).map(|_| start)
+    }
+    read(&mut file, &mut header, 2)?;
+    if header == [0xFF, jpeg::markers::SOI] {
+        loop {
+            let at = read(&mut file, &mut header, 2)?;
+            let marker = header[at + 1];
+            if marker == jpeg::markers::SOS || marker == jpeg::markers::EOI {
+                header.truncate(at);
+                break
+            }
+            // TEM and RSTn stand alone, every other segment gives its length
+            if matches!(marker, 0x01 | 0xD0..=0xD7) { continue }
+            let at = read(&mut file, &mut header, 2)?;
+            let length = u16::from_be_bytes([header[at], header[at + 1]]) as usize;
+            read(&mut file, &mut header, length.saturating_sub(2))?;
+        }
+        header.extend([0xFF, jpeg::markers::EOI]);
+        return Ok(header)
+    }
+    read(&mut file, &mut header, 6)?;
+    if header == PNG_SIGNATURE {
+        loop {
+            let at = read(&mut file, &mut header, 8)?;
+            let length = u32::from_be_bytes(header[at..at + 4].try_into().unwrap()) as usize;
+            if [b"IDAT", b"IEND"].contains(&&header[at + 4..at + 8].try_into().unwrap()) {
+                header.truncate(at);
+                break
+            }
+            read(&mut file, &mut header, length + 4)?;
+        }
+        header.extend(PNG_IEND);
+        return Ok(header)
+    }
+    file.read_to_end(&mut header)?;
+    Ok(header)
+}
+
+/// Width and height of the image whose file starts with `header`, as read by
+/// `read_header`.
+pub fn image_size(header: &[u8]) -> Option<(u32, u32)> {
+    if let Ok(jpeg) = Jpeg::from_bytes(header.to_owned().into()) {
+        // Start of frame, in any of its variants
+        let is_sof = |marker| matches!(marker, 0xC0..=0xCF) && ![0xC4, 0xC8, 0xCC].contains(&marker);
+        let sof = jpeg.segments().iter().find(|s| is_sof(s.marker()))?.contents();
+        let [_precision, h1, h0, w1, w0, ..] = sof[..] else { return None };
+        return Some((u16::from_be_bytes([w1, w0]) as u32, u16::from_be_bytes([h1, h0]) as u32))
+    }
+    if header.starts_with(&PNG_SIGNATURE) {
+        let be = |at: usize| Some(u32::from_be_bytes(header.get(at..at + 4)?.try_into().unwrap()));
+        // IHDR always comes first
+        return Some((be(16)?, be(20)?))
+    }
+    image::ImageReader::new(std::io::Cursor::new(header)).with_guessed_format().ok()?.into_dimensions().ok()
+}
+
+/// Where the metadata of the photos of a class is kept.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Storage {
+    /// Inside the photos themselves, for JPEG and PNG; like `SidecarPerImage`
+    /// for other formats
+    #[default]
+    Embedded,
+    /// In a separate file next to each photo
+    SidecarPerImage,
+    /// In a single file next to all the photos
+    SidecarPerClass,
+}
+
+impl Storage {
+    /// The store for the photos in `photo_dir`
+    pub fn open(self, photo_dir: impl AsRef<Path>) -> Box<dyn MetadataStore> {
+        match self {
+            Storage::Embedded        => Box::new(Embedded),
+            Storage::SidecarPerImage => Box::new(SidecarPerImage),
+            Storage::SidecarPerClass => Box::new(SidecarPerClass::new(photo_dir.as_ref().join(CLASS_SIDECAR))),
+        }
+    }
+}
+
+/// Common interface to the different ways of storing `Metadata`.
+pub trait MetadataStore {
+    fn load  (&self, photo: &Path) -> Option<Metadata>;
+    fn save  (&self, photo: &Path, metadata: &Metadata);
+    fn remove(&self, photo: &Path);
+    /// The file in which the metadata of `photo` is kept
+    fn location(&self, photo: &Path) -> PathBuf;
+
+    /// Save the metadata of many photos, which some stores do more efficiently
+    /// than one by one.
+    fn save_all(&self, photos: &[(&Path, &Metadata)]) {
+        for (photo, metadata) in photos { self.save(photo, metadata); }
+    }
+
+    fn remove_all(&self, photos: &[&Path]) {
+        for photo in photos { self.remove(photo); }
+    }
+}
+
+/// Keeps metadata in a segment inside each JPEG or a chunk inside each PNG, and
+/// in sidecars for formats without room for it.
+pub struct Embedded;
+
+impl MetadataStore for Embedded {
+    fn load(&self, photo: &Path) -> Option<Metadata> {
+        match ImageFormat::from_path(photo) {
+            Ok(ImageFormat::Jpeg) => find_in_jpeg(&or_warn(read_jpeg_header(photo), photo)?, photo),
+            Ok(ImageFormat::Png ) => find_in_png (&or_warn(read_png_header (photo), photo)?, photo),
+            _                     => SidecarPerImage.load(photo),
+        }
+    }
+
+    fn save(&self, photo: &Path, metadata: &Metadata) {
+        match ImageFormat::from_path(photo) {
+            Ok(ImageFormat::Jpeg) => embed_in_jpeg_file(photo, metadata),
+            Ok(ImageFormat::Png ) => embed_in_png_file (photo, metadata),
+            _                     => SidecarPerImage.save(photo, metadata),
+        }
+    }
+
+    fn remove(&self, photo: &Path) {
+        match ImageFormat::from_path(photo) {
+            Ok(ImageFormat::Jpeg) => strip_from_jpeg_file(photo),
+            Ok(ImageFormat::Png ) => strip_from_png_file (photo),
+            _                     => SidecarPerImage.remove(photo),
+        }
+    }
+
+    fn location(&self, photo: &Path) -> PathBuf {
+        match ImageFormat::from_path(photo) {
+            Ok(ImageFormat::Jpeg | ImageFormat::Png) => photo.into(),
+            _                                        => SidecarPerImage.location(photo),
+        }
+    }
+}
+
+/// Keeps the metadata of `photo.jpg` in `photo.jpg.trombinoscope`.
+pub struct SidecarPerImage;
+
+impl SidecarPerImage {
+    fn sidecar(photo: &Path) -> PathBuf {
+        let mut name = photo.as_os_str().to_owned();
+        name.push(".");
+        name.push(OUR_LABEL);
+        name.into()
+    }
+}
+
+impl MetadataStore for SidecarPerImage {
+    fn load(&self, photo: &Path) -> Option<Metadata> {
+        let sidecar = Self::sidecar(photo);
+        let bytes = std::fs::read(&sidecar).ok()?;
+        decode_reporting(&bytes, sidecar)
+    }
+
+    fn save(&self, photo: &Path, metadata: &Metadata) {
+        std::fs::write(Self::sidecar(photo), encode(metadata)).unwrap();
+    }
+
+    fn remove(&self, photo: &Path) {
+        let _ = std::fs::remove_file(Self::sidecar(photo));
+    }
+
+    fn location(&self, photo: &Path) -> PathBuf { Self::sidecar(photo) }
+}
+
+/// Name of the file used by `SidecarPerClass`, in the directory of the photos
+pub const CLASS_SIDECAR: &str = "trombinoscope.metadata";
+
+/// Keeps the metadata of all the photos in a directory in a single file,
+/// indexed by the photos' file names.
+pub struct SidecarPerClass {
+    file: PathBuf,
+    /// Contents of `file`, once it has been read
+    entries: RefCell<Option<BTreeMap<String, Vec<u8>>>>,
+}
+
+impl SidecarPerClass {
+    pub fn new(file: PathBuf) -> Self { Self { file, entries: RefCell::default() } }
+
+    /// Apply `f` to the entries, which are only read from the file the first time.
+    fn with_entries<T>(&self, f: impl FnOnce(&mut BTreeMap<String, Vec<u8>>) -> T) -> T {
+        f(self.entries.borrow_mut().get_or_insert_with(|| self.read()))
+    }
+
+    fn read(&self) -> BTreeMap<String, Vec<u8>> {
+        let Ok(bytes) = std::fs::read(&self.file) else { return BTreeMap::new() };
+        bitcode::decode(&bytes).unwrap_or_else(|err| {
+            // Carrying on would overwrite the crops of the whole class
+            panic!("{} is corrupt, refusing to touch it: {err}", self.file.display())
+        })
+    }
+
+    fn write(&self, entries: &BTreeMap<String, Vec<u8>>) {
+        write_atomically(&self.file, &bitcode::encode(entries))
+            .unwrap_or_else(|err| panic!("Could not write {}: {err}", self.file.display()));
+    }
+
+    fn key(photo: &Path) -> String { photo.file_name().unwrap().to_string_lossy().into() }
+}
+
+impl MetadataStore for SidecarPerClass {
+    fn load(&self, photo: &Path) -> Option<Metadata> {
+        let bytes = self.with_entries(|entries| entries.get(&Self::key(photo)).cloned())?;
+        decode_reporting(&bytes, photo)
+    }
+
+    fn save(&self, photo: &Path, metadata: &Metadata) { self.save_all(&[(photo, metadata)]); }
+    fn remove(&self, photo: &Path) { self.remove_all(&[photo]); }
+
+    fn save_all(&self, photos: &[(&Path, &Metadata)]) {
+        if photos.is_empty() { return }
+        self.with_entries(|entries| {
+            for (photo, metadata) in photos { entries.insert(Self::key(photo), encode(metadata)); }
+            self.write(entries);
+        });
+    }
+
+    fn remove_all(&self, photos: &[&Path]) {
+        self.with_entries(|entries| {
+            let removed = photos.iter().filter(|photo| entries.remove(&Self::key(photo)).is_some()).count();
+            if removed > 0 { self.write(entries); }
+        });
+    }
+
+    fn location(&self, _photo: &Path) -> PathBuf { self.file.clone() }
+}
+
+/// Decode a payload which is known to be ours, reporting any problems.
+fn decode_reporting(bytes: &[u8], source: impl AsRef<Path>) -> Option<Metadata> {
+    let source = source.as_ref().display();
+    match decode(bytes) {
+        Decoded::Current (metadata) => Some(metadata),
+        Decoded::Migrated(metadata) => { println!("Found old metadata in {source}, to be upgraded when next saved"); Some(metadata) },
+        Decoded::Corrupt(err) => { println!("WARNING: ignoring corrupt metadata in {source}: {err}"); None },
+        Decoded::Foreign      => { println!("WARNING: ignoring unrecognized metadata in {source}"); None },
+    }
+}
+
+/// Replace the contents of the file at `path` with `bytes`, so that a crash
+/// part way through leaves either the old contents or the new, never a mixture.
+fn write_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)
+}
+
+/// Move the metadata of `photos` from one store to another.
+pub fn convert(photos: &[PathBuf], from: &dyn MetadataStore, to: &dyn MetadataStore) {
+    let moving = photos.iter()
+        // Both stores keep some formats in the same place, where there is
+        // nothing to do
+        .filter(|photo| from.location(photo) != to.location(photo))
+        .filter_map(|photo| Some((photo.as_path(), from.load(photo)?)))
+        .collect::<Vec<_>>();
+    // Only removed once safely in the new store: `save_all` panics if it fails
+    to.save_all(&moving.iter().map(|(photo, metadata)| (*photo, metadata)).collect::<Vec<_>>());
+    from.remove_all(&moving.iter().map(|&(photo, _)| photo).collect::<Vec<_>>());
+    for (photo, _) in &moving { println!("Converted metadata of {}", photo.display()); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::TestDir;
+    use rstest::rstest;
+    use pretty_assertions::assert_eq;
+
+    fn metadata() -> Metadata {
+        Metadata {
+            given: "Zoé".into(),
+            family: "Martin".into(),
+            crop: CropBox::Fraction { x: 0.5, y: 0.4, w: 0.3 },
+            r: (5, 4),
+            orientation: Orientation::from_exif(6),
+            reviewed: true,
+            excluded: true,
+            adjustments: Some(Adjustments { gamma: 1.2, ..Adjustments::default() }),
+            default_adjustments: Some(Adjustments { temperature: 0.1, ..Adjustments::default() }),
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        assert_eq!(decode(&encode(&metadata())), Decoded::Current(metadata()));
+    }
+
+    #[test]
+    fn adobe_segment_is_foreign() {
+        let adobe = b"Adobe\x00\x64\x80\x00\x00\x00\x01";
+        assert_eq!(decode(adobe), Decoded::Foreign);
+    }
+
+    #[test]
+    fn unlabelled_segments_are_migrated() {
+        let Metadata { given, family, .. } = metadata();
+        let old = bitcode::encode(&MetadataV0 { given, family, x: 1000, y: 800, w: 600 });
+        let Decoded::Migrated(migrated) = decode(&old) else { panic!("Old metadata not migrated") };
+        assert_eq!(migrated.orientation, Orientation::from_exif(8));
+        assert_eq!(migrated.crop, CropBox::Pixels { x: 1000, y: 800, w: 600 });
+    }
+
+    #[test]
+    fn fractions_survive_converting_to_pixels_and_back() {
+        let size = (4031, 3023);
+        let crop = CropBox::from_pixels((1234.5678, 987.654, 1111.1), size);
+        assert_eq!(CropBox::from_pixels(crop.in_pixels(size), size), crop);
+    }
+
+    #[test]
+    fn damaged_payload_is_corrupt() {
+        let mut bytes = encode(&metadata());
+        bytes.truncate(bytes.len() - 3);
+        assert!(matches!(decode(&bytes), Decoded::Corrupt(_)));
+    }
+
+    #[test]
+    fn future_version_is_corrupt() {
+        let mut bytes = encode(&metadata());
+        bytes[OUR_LABEL.len() + 1] = FORMAT_VERSION + 1;
+        assert!(matches!(decode(&bytes), Decoded::Corrupt(_)));
+    }
+
+    #[rstest]
+    #[case("jpg" , true )]
+    #[case("png" , true )]
+    #[case("tiff", false)]
+    fn convert_between_stores(#[case] extension: &str, #[case] embeddable: bool) {
+        let dir = TestDir::new();
+        let photo = dir.join(format!("Zoé @ Martin.{extension}"));
+        image::RgbImage::new(16, 16).save(&photo).unwrap();
+        let photos = [photo.clone()];
+
+        let embedded  = Storage::Embedded       .open(&dir);
+        let per_image = Storage::SidecarPerImage.open(&dir);
+        let per_class = Storage::SidecarPerClass.open(&dir);
+
+        embedded.save(&photo, &metadata());
+        convert(&photos, &*embedded, &*per_image);
+        // Formats which cannot hold metadata use the same sidecar in both stores
+        assert_eq!(embedded .load(&photo), (!embeddable).then(metadata));
+        assert_eq!(per_image.load(&photo), Some(metadata()));
+        convert(&photos, &*per_image, &*per_class);
+        assert_eq!(per_image.load(&photo), None);
+        assert_eq!(embedded .load(&photo), None);
+        assert_eq!(per_class.load(&photo), Some(metadata()));
+        convert(&photos, &*per_class, &*embedded);
+        assert_eq!(per_class.load(&photo), None);
+        assert_eq!(embedded .load(&photo), Some(metadata()));
+        assert!(image::open(&photo).is_ok());
+
+        let content = image_content(&std::fs::read(&photo).unwrap());
+        embedded.save(&photo, &Metadata { reviewed: false, ..metadata() });
+        assert_eq!(image_content(&std::fs::read(&photo).unwrap()), content);
+    }
+
+    #[rstest]
+    #[case("jpg" )]
+    #[case("png" )]
+    #[case("tiff")]
+    fn header_has_the_size_and_our_metadata(#[case] extension: &str) {
+        let dir = TestDir::new();
+        let photo = dir.join(format!("Zoé @ Martin.{extension}"));
+        image::RgbImage::from_fn(300, 200, |x, y| [x as u8, y as u8, (x ^ y) as u8].into()).save(&photo).unwrap();
+        Embedded.save(&photo, &metadata());
+
+        let header = read_header(&photo).unwrap();
+        assert_eq!(image_size(&header), Some((300, 200)));
+        assert_eq!(Embedded.load(&photo), Some(metadata()));
+        if extension != "tiff" { assert!(header.len() < 1000, "{} bytes", header.len()); }
+    }
+
+    #[test]
+    fn class_sidecar_is_read_once_and_replaced_whole() {
+        let dir = TestDir::new();
+        let photos = ["a.jpg", "b.jpg", "c.jpg"].map(|name| dir.join(name));
+        let store = Storage::SidecarPerClass.open(&dir);
+        let metadata = metadata();
+        store.save_all(&photos.iter().map(|p| (p.as_path(), &metadata)).collect::<Vec<_>>());
+        store.remove(&photos[1]);
+        // Served from memory, once read
+        assert_eq!(store.load(&photos[0]), Some(metadata.clone()));
+        std::fs::write(dir.join(CLASS_SIDECAR), b"overwritten by someone else").unwrap();
+        assert_eq!(store.load(&photos[0]), Some(metadata.clone()));
+        store.save(&photos[2], &metadata);
+        let reopened = Storage::SidecarPerClass.open(&dir);
+        assert_eq!(photos.each_ref().map(|p| reopened.load(p)), [Some(metadata.clone()), None, Some(metadata)]);
+        let files = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect::<Vec<_>>();
+        assert_eq!(files, [CLASS_SIDECAR]);
+    }
+
+    #[rstest]
+    #[case::jpeg_segment_past_the_end("jpg", b"\xFF\xD8\xFF\xE1\x01\x00Exif")]
+    #[case::png_without_chunks       ("png", b"\x89PNG\r\n\x1a\n\0\0")]
+    fn malformed_photos_have_no_metadata(#[case] extension: &str, #[case] bytes: &[u8]) {
+        let dir = TestDir::new();
+        let photo = dir.join(format!("Zoé @ Martin.{extension}"));
+        std::fs::write(&photo, bytes).unwrap();
+        assert_eq!(Embedded.load(&photo), None);
+        Embedded.remove(&photo);
+        assert_eq!(std::fs::read(&photo).unwrap(), bytes);
+    }
+
+    #[rstest]
+    #[case("jpg" )]
+    #[case("png" )]
+    #[case("webp")]
+    fn embedded_and_sidecar_round_trip(#[case] extension: &str) {
+        let dir = TestDir::new();
+        let photo = dir.join(format!("Zoé @ Martin.{extension}"));
+        image::RgbImage::new(16, 16).save(&photo).unwrap();
+        let photos = [photo.clone()];
+
+        let embedded  = Storage::Embedded       .open(&dir);
+        let per_image = Storage::SidecarPerImage.open(&dir);
+
+        embedded.save(&photo, &metadata());
+        for _ in 0..2 {
+            convert(&photos, &*embedded, &*per_image);
+            assert_eq!(per_image.load(&photo), Some(metadata()));
+            convert(&photos, &*per_image, &*embedded);
+            assert_eq!(embedded .load(&photo), Some(metadata()));
+        }
+        // Only the format without room for metadata leaves a sidecar behind
+        let sidecar = per_image.location(&photo);
+        assert_eq!(sidecar.exists(), extension == "webp");
+        assert!(image::open(&photo).is_ok());
+    }
+}
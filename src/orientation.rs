@@ -0,0 +1,98 @@
+use std::io::Cursor;
+
+use bitcode::{Encode, Decode};
+use image::DynamicImage;
+
+/// How a stored image must be transformed to be displayed upright.
+///
+/// Covers all 8 values of the EXIF Orientation tag: some number of clockwise
+/// quarter turns, optionally followed by a horizontal flip.
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Orientation {
+    quarter_turns: u8,
+    mirror: bool,
+}
+
+impl Orientation {
+    pub fn from_exif(tag: u32) -> Option<Self> {
+        let (quarter_turns, mirror) = match tag {
+            1 => (0, false),
+            2 => (0, true ),
+            3 => (2, false),
+            4 => (2, true ),
+            5 => (1, true ),
+            6 => (1, false),
+            7 => (3, true ),
+            8 => (3, false),
+            _ => return None,
+        };
+        Some(Self { quarter_turns, mirror })
+    }
+
+    /// Read the EXIF Orientation tag from the contents of an image file.
+    pub fn read_exif(file_contents: &[u8]) -> Option<Self> {
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(file_contents))
+            .ok()?;
+        let field = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?;
+        Self::from_exif(field.value.get_uint(0)?)
+    }
+
+    /// The orientation which results from turning the image displayed with
+    /// `self` by a further quarter turn clockwise.
+    pub fn rotated_clockwise(self) -> Self {
+        let Self { quarter_turns: q, mirror } = self;
+        // Turning after a flip is the same as flipping after turning the other way
+        let quarter_turns = if mirror { (q + 3) % 4 } else { (q + 1) % 4 };
+        Self { quarter_turns, mirror }
+    }
+
+    /// Size of an image of size `(w, h)`, once `self` has been applied to it
+    pub fn apply_to_size(self, (w, h): (u32, u32)) -> (u32, u32) {
+        if self.quarter_turns % 2 == 0 { (w, h) } else { (h, w) }
+    }
+
+    pub fn apply(self, image: DynamicImage) -> DynamicImage {
+        let image = match self.quarter_turns {
+            0 => image,
+            1 => image.rotate90(),
+            2 => image.rotate180(),
+            3 => image.rotate270(),
+            _ => unreachable!("quarter_turns is always kept below 4"),
+        };
+        if self.mirror { image.fliph() } else { image }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use pretty_assertions::assert_eq;
+
+    #[rstest]
+    #[case(1, 6)]
+    #[case(6, 3)]
+    #[case(3, 8)]
+    #[case(8, 1)]
+    #[case(2, 7)]
+    #[case(7, 4)]
+    #[case(4, 5)]
+    #[case(5, 2)]
+    fn rotate_clockwise(#[case] before: u32, #[case] after: u32) {
+        let before = Orientation::from_exif(before).unwrap();
+        let after  = Orientation::from_exif(after ).unwrap();
+        assert_eq!(before.rotated_clockwise(), after);
+    }
+
+    #[rstest]
+    #[case(1)] #[case(2)] #[case(3)] #[case(4)]
+    #[case(5)] #[case(6)] #[case(7)] #[case(8)]
+    fn rotate_clockwise_matches_pixels(#[case] tag: u32) {
+        let image = DynamicImage::ImageLuma8(image::GrayImage::from_fn(3, 2, |x, y| [(10 * y + x) as u8].into()));
+        let orientation = Orientation::from_exif(tag).unwrap();
+        let expected = orientation.apply(image.clone()).rotate90();
+        let actual   = orientation.rotated_clockwise().apply(image);
+        assert_eq!(actual, expected);
+    }
+}
@@ -1,4 +1,5 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub fn filename_to_given_family(path: impl AsRef<Path>) -> Option<(String, String)> {
     let basename = path.as_ref().file_name()?;
@@ -10,6 +11,48 @@ pub fn filename_to_given_family(path: impl AsRef<Path>) -> Option<(String, Strin
     ))
 }
 
+/// Directory of its own for the files of a test, removed along with them when
+/// dropped, even if the test fails.
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new() -> Self {
+        // Unique among the tests running in parallel, in this process or others
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("trombinoscope-test-{}-{n}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    /// `n` small photos, each of a different colour, named after made up
+    /// students
+    pub fn photos(&self, n: usize) -> Vec<PathBuf> {
+        (0..n).map(|i| {
+            let path = self.join(format!("{i} @ Photo.png"));
+            image::RgbImage::from_pixel(40, 30, [i as u8, 0, 0].into()).save(&path).unwrap();
+            path
+        }).collect()
+    }
+}
+
+impl std::ops::Deref for TestDir {
+    type Target = Path;
+    fn deref(&self) -> &Path { &self.0 }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path { &self.0 }
+}
+
+impl Default for TestDir {
+    fn default() -> Self { Self::new() }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) { _ = std::fs::remove_dir_all(&self.0); }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;